@@ -0,0 +1,226 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+//! `pam_bunlock.so`: authenticates by the presence of the user's trusted Bluetooth
+//! device.
+//!
+//! The module reads the user's BUnlock config from `~/.config/bunlock`, so it works
+//! for any service (sudo, polkit, login, screen lockers). In `proximity` mode it
+//! succeeds when the device is in range. In `second_factor` mode it never succeeds
+//! on its own: it is ignored when the device is in range and denies otherwise.
+//! Proximity unlocks are subject to the same policies as the daemon's (schedule,
+//! network, recent password authentication and rate limits) and are recorded in the
+//! user's audit log.
+//! Users without a config are ignored, so that they can still log in.
+//! Arguments: `timeout=<seconds>` limits how long to look for the device (default 5).
+
+use bunlock::unlocker::bluetooth::device::Device;
+use bunlock::unlocker::config::{self, Config, UnlockMode};
+use bunlock::unlocker::policy::rate_limit::{self, RateLimiter};
+use bunlock::unlocker::policy::{self, auth};
+use bunlock::unlocker::{audit, paths, presence, privileges};
+use libc::{c_char, c_int};
+use std::error::Error;
+use std::ffi::{CStr, CString};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+
+const PAM_SUCCESS: c_int = 0;
+const PAM_SERVICE_ERR: c_int = 3;
+const PAM_AUTH_ERR: c_int = 7;
+const PAM_AUTHINFO_UNAVAIL: c_int = 9;
+const PAM_USER_UNKNOWN: c_int = 10;
+const PAM_IGNORE: c_int = 25;
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Opaque `pam_handle_t`.
+#[repr(C)]
+pub struct PamHandle {
+    _private: [u8; 0],
+}
+
+// Provided by libpam, which is always loaded by the application using the module.
+extern "C" {
+    fn pam_get_user(pamh: *mut PamHandle, user: *mut *const c_char, prompt: *const c_char) -> c_int;
+}
+
+struct Options {
+    timeout: Duration,
+}
+
+impl Options {
+    fn parse(args: &[String]) -> Options {
+        let mut options = Options { timeout: DEFAULT_TIMEOUT };
+        for arg in args {
+            match arg.split_once('=') {
+                Some(("timeout", value)) => match value.parse() {
+                    Ok(seconds) => options.timeout = Duration::from_secs(seconds),
+                    Err(_) => log(libc::LOG_WARNING, &format!("invalid timeout: {}", value)),
+                },
+                _ => log(libc::LOG_WARNING, &format!("unknown argument: {}", arg)),
+            }
+        }
+        options
+    }
+}
+
+/// # Safety
+/// Called by libpam with a valid handle and `argc` valid C strings in `argv`.
+#[no_mangle]
+pub unsafe extern "C" fn pam_sm_authenticate(
+    pamh: *mut PamHandle,
+    _flags: c_int,
+    argc: c_int,
+    argv: *const *const c_char,
+) -> c_int {
+    catch_unwind(AssertUnwindSafe(|| {
+        let options = Options::parse(&arguments(argc, argv));
+        authenticate(pamh, &options)
+    }))
+    .unwrap_or(PAM_SERVICE_ERR)
+}
+
+#[no_mangle]
+pub extern "C" fn pam_sm_setcred(
+    _pamh: *mut PamHandle,
+    _flags: c_int,
+    _argc: c_int,
+    _argv: *const *const c_char,
+) -> c_int {
+    PAM_SUCCESS
+}
+
+fn authenticate(pamh: *mut PamHandle, options: &Options) -> c_int {
+    let Some(user) = user_name(pamh) else {
+        return PAM_USER_UNKNOWN;
+    };
+    let Some((home, uid, gid)) = paths::lookup_user(&user) else {
+        return PAM_USER_UNKNOWN;
+    };
+    let config = match read_config(&home, uid, gid) {
+        Ok(Some(config)) => config,
+        Ok(None) => {
+            log(libc::LOG_INFO, &format!("no config for {} in {}", user, paths::config_dir_in(&home).display()));
+            return PAM_IGNORE;
+        }
+        Err(e) => {
+            log(libc::LOG_ERR, &format!("failed to read the config of {}: {}", user, e));
+            return PAM_AUTHINFO_UNAVAIL;
+        }
+    };
+    let runtime = match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            log(libc::LOG_ERR, &format!("failed to start the runtime: {}", e));
+            return PAM_SERVICE_ERR;
+        }
+    };
+    let device = match runtime.block_on(presence::read(&config, options.timeout)) {
+        Ok(device) => device,
+        Err(e) => {
+            log(libc::LOG_ERR, &format!("presence check for {} failed: {}", user, e));
+            return PAM_AUTHINFO_UNAVAIL;
+        }
+    };
+    if config.unlock_mode != UnlockMode::Proximity {
+        if config.is_in_range(&device) {
+            log(libc::LOG_NOTICE, &format!("trusted device of {} is present, password still required", user));
+            return PAM_IGNORE;
+        }
+        log(libc::LOG_NOTICE, &format!("trusted device of {} is not in range", user));
+        return PAM_AUTH_ERR;
+    }
+    match decide(&runtime, &config, &device, &home, uid, gid) {
+        Ok(None) => {
+            log(libc::LOG_NOTICE, &format!("trusted device of {} is present", user));
+            PAM_SUCCESS
+        }
+        Ok(Some(reason)) => {
+            log(libc::LOG_NOTICE, &format!("unlock of {} denied: {}", user, reason));
+            PAM_AUTH_ERR
+        }
+        Err(e) => {
+            log(libc::LOG_ERR, &format!("failed to apply the policies of {}: {}", user, e));
+            PAM_AUTHINFO_UNAVAIL
+        }
+    }
+}
+
+/// Decides a proximity unlock like the daemon does and records it in the user's audit
+/// log, as the user. Returns why it was denied, if it was. The rate limits count the
+/// unlocks in the audit log; a suspension after repeated re-locks is only known to the
+/// daemon.
+fn decide(
+    runtime: &Runtime,
+    config: &Config,
+    device: &Device,
+    home: &Path,
+    uid: u32,
+    gid: u32,
+) -> Result<Option<String>, Box<dyn Error>> {
+    let _user = privileges::EffectiveUser::switch(uid, gid)?;
+    let audit_log = audit::audit_log_path_in(home);
+    let reason = if config.is_in_range(device) {
+        let unlocks = audit::recent_unlocks(&audit_log, rate_limit::WINDOW)?;
+        let mut rate_limiter = RateLimiter::with_unlocks(unlocks, Instant::now());
+        let last_auth = auth::last_auth_path_in(home);
+        runtime
+            .block_on(policy::denial_reason(config, &mut rate_limiter, &last_auth))
+            .unwrap_or_else(|e| Some(format!("network state unknown ({})", e)))
+    } else {
+        Some("device out of range".to_string())
+    };
+    let record = match &reason {
+        Some(reason) => audit::Record::unlock(config, device, "deny", reason),
+        None => audit::Record::unlock(config, device, "unlock", "device in range"),
+    };
+    if let Err(e) = audit::append(&audit_log, &record) {
+        log(libc::LOG_ERR, &format!("failed to write the audit log: {}", e));
+    }
+    Ok(reason)
+}
+
+/// Reads the user's config as the user, without ever writing to their files.
+fn read_config(home: &Path, uid: u32, gid: u32) -> Result<Option<Config>, Box<dyn Error>> {
+    let _user = privileges::EffectiveUser::switch(uid, gid)?;
+    let config_path = config::config_path_in(&paths::config_dir_in(home));
+    if !config_path.exists() {
+        return Ok(None);
+    }
+    Config::load_read_only(&config_path).map(Some)
+}
+
+fn user_name(pamh: *mut PamHandle) -> Option<String> {
+    let mut user: *const c_char = std::ptr::null();
+    let status = unsafe { pam_get_user(pamh, &mut user, std::ptr::null()) };
+    if status != PAM_SUCCESS || user.is_null() {
+        return None;
+    }
+    let user = unsafe { CStr::from_ptr(user) };
+    user.to_str().ok().map(str::to_string)
+}
+
+unsafe fn arguments(argc: c_int, argv: *const *const c_char) -> Vec<String> {
+    if argv.is_null() {
+        return vec![];
+    }
+    (0..argc as usize)
+        .map(|i| CStr::from_ptr(*argv.add(i)).to_string_lossy().into_owned())
+        .collect()
+}
+
+fn log(priority: c_int, message: &str) {
+    let Ok(message) = CString::new(format!("pam_bunlock: {}", message)) else {
+        return;
+    };
+    unsafe { libc::syslog(libc::LOG_AUTHPRIV | priority, c"%s".as_ptr(), message.as_ptr()) }
+}
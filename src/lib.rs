@@ -0,0 +1,10 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+pub mod unlocker;
@@ -7,9 +7,11 @@
  * See the LICENSE file for details.
  */
 
-use clap::Command;
+use clap::{value_parser, Arg, ArgAction, Command};
 use std::error::Error;
-mod unlocker;
+use std::path::PathBuf;
+use std::time::Duration;
+use bunlock::unlocker;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
@@ -17,32 +19,245 @@ async fn main() -> Result<(), Box<dyn Error>> {
         .version("0.2.0")
         .author("Ilia MatthewAllDev Kuvarzin <luceo2011@yandex.ru>")
         .about("A tool for unlocking your system using a Bluetooth device")
-        .subcommand(Command::new("config").about("Runs the configuration setup"))
+        .arg(
+            Arg::new("config_path")
+                .long("config")
+                .value_name("PATH")
+                .global(true)
+                .value_parser(value_parser!(PathBuf))
+                .help("Path to the config file (also settable with BUNLOCK_CONFIG)"),
+        )
+        .subcommand(
+            Command::new("config")
+                .about("Runs the configuration setup")
+                .subcommand(Command::new("check").about("Check the config file for errors"))
+                .subcommand(
+                    Command::new("convert")
+                        .about("Convert the config file to another format")
+                        .arg(
+                            Arg::new("format")
+                                .required(true)
+                                .value_parser(["toml", "json"])
+                                .help("Target format"),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("auth")
+                .about("Track password authentication")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("record")
+                        .about("Record a password authentication now (for pam_exec)"),
+                ),
+        )
+        .subcommand(
+            Command::new("check")
+                .about("Check whether the trusted device is in range (exit status 0 if it is)")
+                .arg(
+                    Arg::new("timeout")
+                        .long("timeout")
+                        .value_name("SECONDS")
+                        .default_value("5")
+                        .value_parser(value_parser!(u64))
+                        .help("How long to look for the device"),
+                ),
+        )
+        .subcommand(
+            Command::new("approve")
+                .about("Ask the running daemon to approve an action (exit status 0 if allowed)")
+                .arg(Arg::new("action").required(true).help("Short name of the action, shown in the prompt"))
+                .arg(Arg::new("message").default_value("").help("Details shown in the prompt")),
+        )
+        .subcommand(
+            Command::new("history")
+                .about("Show recorded unlock decisions and approvals")
+                .arg(
+                    Arg::new("limit")
+                        .long("limit")
+                        .short('n')
+                        .value_name("COUNT")
+                        .default_value("20")
+                        .value_parser(value_parser!(usize))
+                        .help("Show only the newest COUNT records (0 for all)"),
+                )
+                .arg(
+                    Arg::new("event")
+                        .long("event")
+                        .value_parser(["unlock", "approval"])
+                        .help("Show only this kind of record"),
+                )
+                .arg(
+                    Arg::new("decision")
+                        .long("decision")
+                        .value_parser(["unlock", "deny", "error", "allow"])
+                        .help("Show only records with this decision"),
+                )
+                .arg(
+                    Arg::new("since")
+                        .long("since")
+                        .value_name("TIME")
+                        .help("Show only records from TIME on (YYYY-MM-DD or \"YYYY-MM-DD HH:MM\")"),
+                )
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Print the records as JSON lines"),
+                ),
+        )
+        .subcommand(
+            Command::new("logs")
+                .about("Show the service's log from the journal")
+                .arg(
+                    Arg::new("follow")
+                        .long("follow")
+                        .short('f')
+                        .action(ArgAction::SetTrue)
+                        .help("Keep printing new entries"),
+                )
+                .arg(
+                    Arg::new("since")
+                        .long("since")
+                        .value_name("TIME")
+                        .help("Show entries from TIME on, e.g. \"today\" or \"1 hour ago\""),
+                )
+                .arg(
+                    Arg::new("level")
+                        .long("level")
+                        .value_parser(unlocker::logs::LEVELS.map(|(name, _)| name))
+                        .help("Hide entries less important than this"),
+                )
+                .arg(
+                    Arg::new("lines")
+                        .long("lines")
+                        .short('n')
+                        .value_name("COUNT")
+                        .value_parser(value_parser!(usize))
+                        .help("Show only the newest COUNT entries"),
+                )
+                .arg(
+                    Arg::new("decisions")
+                        .long("decisions")
+                        .action(ArgAction::SetTrue)
+                        .help("Show only unlock decisions and approvals"),
+                ),
+        )
+        .subcommand(Command::new("status").about("Show the device, service and unlock policy status"))
         .subcommand(
             Command::new("service")
                 .about("Manage the systemd service")
-                .subcommand(Command::new("enable").about("Enable and create the service if it doesn't exist"))
+                .subcommand(Command::new("enable").about("Enable the service, creating or updating its unit file"))
                 .subcommand(Command::new("disable").about("Disable the service"))
                 .subcommand(Command::new("start").about("Start the service"))
                 .subcommand(Command::new("stop").about("Stop the service"))
                 .subcommand(Command::new("restart").about("Restart the service"))
+                .subcommand(Command::new("reload").about("Reload the configuration of the running service"))
                 .subcommand(Command::new("is_active").about("Check if the service is active"))
                 .subcommand(Command::new("remove").about("Remove the service")),
         )
         .get_matches();
 
+    let config_override = matches.get_one::<PathBuf>("config_path").map(PathBuf::as_path);
+    let config_path = unlocker::config::config_path(config_override);
+
     match matches.subcommand() {
-        Some(("config", _)) => {
-            let mut config_data = unlocker::config::Config::new().await?;
-            config_data.configurate().await?;
+        Some(("config", config_matches)) => {
+            match config_matches.subcommand() {
+                // Checking must not migrate and save the file.
+                Some(("check", _)) => match unlocker::config::Config::load_read_only(&config_path) {
+                    Ok(_) => println!("{} is valid.", config_path.display()),
+                    Err(e) => {
+                        println!("{}", e);
+                        std::process::exit(1)
+                    }
+                },
+                Some(("convert", convert_matches)) => {
+                    let config_data = unlocker::config::Config::read(&config_path).await?;
+                    let name = convert_matches.get_one::<String>("format").expect("required");
+                    let format = unlocker::config::ConfigFormat::from_name(name).ok_or("Unknown format")?;
+                    let path = config_data.convert(format).await?;
+                    println!("Config converted to {}: {}", format, path.display());
+                    if unlocker::service::exists()? {
+                        unlocker::service::create_service(&path)?;
+                        println!("Restart the service for it to use the new file.");
+                    }
+                }
+                _ => unlocker::config::Config::read(&config_path).await?.configurate().await?,
+            }
+        }
+        Some(("auth", auth_matches)) => {
+            if let Some(("record", _)) = auth_matches.subcommand() {
+                unlocker::policy::auth::record_from_pam()?;
+            }
+        }
+        Some(("check", check_matches)) => {
+            let config_data = unlocker::config::Config::new(&config_path).await?;
+            let timeout = *check_matches.get_one::<u64>("timeout").expect("has default");
+            if unlocker::presence::check(&config_data, Duration::from_secs(timeout)).await? {
+                println!("{} is in range.", config_data.device);
+            } else {
+                println!("{} is not in range.", config_data.device);
+                std::process::exit(1)
+            }
+        }
+        Some(("approve", approve_matches)) => {
+            let config_data = unlocker::config::Config::load_read_only(&config_path)?;
+            let action = approve_matches.get_one::<String>("action").expect("required");
+            let message = approve_matches.get_one::<String>("message").expect("has default");
+            let timeout = config_data.approval.unwrap_or_default().max_duration() + Duration::from_secs(5);
+            let (allowed, reason) = unlocker::approval::request(action, message, timeout)?;
+            println!("{}: {}", if allowed { "Allowed" } else { "Denied" }, reason);
+            if !allowed {
+                std::process::exit(1)
+            }
+        }
+        Some(("history", history_matches)) => {
+            let limit = *history_matches.get_one::<usize>("limit").expect("has default");
+            let filter = unlocker::audit::Filter {
+                event: history_matches.get_one::<String>("event").cloned(),
+                decision: history_matches.get_one::<String>("decision").cloned(),
+                since: history_matches
+                    .get_one::<String>("since")
+                    .map(|since| unlocker::audit::parse_time(since))
+                    .transpose()?,
+                limit: (limit > 0).then_some(limit),
+            };
+            let path = unlocker::audit::audit_log_path();
+            let records = unlocker::audit::history(&path, &filter)?;
+            if records.is_empty() && !history_matches.get_flag("json") {
+                println!("No records in {}", path.display());
+            }
+            for record in records {
+                if history_matches.get_flag("json") {
+                    println!("{}", serde_json::to_string(&record)?);
+                } else {
+                    println!("{}", record);
+                }
+            }
+        }
+        Some(("logs", logs_matches)) => {
+            let options = unlocker::logs::LogOptions {
+                follow: logs_matches.get_flag("follow"),
+                since: logs_matches.get_one::<String>("since").cloned(),
+                level: logs_matches.get_one::<String>("level").cloned(),
+                lines: logs_matches.get_one::<usize>("lines").copied(),
+                decisions: logs_matches.get_flag("decisions"),
+            };
+            unlocker::logs::print(&options)?;
+        }
+        Some(("status", _)) => {
+            let config_data = unlocker::config::Config::load_read_only(&config_path)?;
+            unlocker::status::print(&config_data).await;
         }
         Some(("service", service_matches)) => {
             match service_matches.subcommand() {
-                Some(("enable", _)) => unlocker::service::enable()?,
+                Some(("enable", _)) => unlocker::service::enable(&config_path)?,
                 Some(("disable", _)) => unlocker::service::disable()?,
                 Some(("start", _)) => unlocker::service::start()?,
                 Some(("stop", _)) => unlocker::service::stop()?,
                 Some(("restart", _)) => unlocker::service::restart()?,
+                Some(("reload", _)) => unlocker::service::reload()?,
                 Some(("is_active", _)) => {
                     match unlocker::service::is_running() {
                         Ok(true) => {
@@ -64,9 +279,12 @@ async fn main() -> Result<(), Box<dyn Error>> {
             }
         }
         _ => {
-            env_logger::init();
-            let config_data = unlocker::config::Config::new().await?;
-            unlocker::start_daemon(&config_data).await?;
+            unlocker::logging::init();
+            // Unlike the other commands, the daemon doesn't fall back to the defaults.
+            let config_data = unlocker::config::Config::load_from_file(&config_path)
+                .await
+                .map_err(|e| format!("Failed to load the config {}: {}", config_path.display(), e))?;
+            unlocker::start_daemon(config_data).await?;
         }
     }
 
@@ -0,0 +1,188 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+use dbus::blocking::Connection;
+use dbus_crossroads::{Crossroads, MethodErr};
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::sync::mpsc;
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use crate::unlocker::audit;
+use crate::unlocker::config::Config;
+use crate::unlocker::{notifications, presence};
+
+pub const BUS_NAME: &str = "io.github.MatthewAllDev.BUnlock";
+pub const OBJECT_PATH: &str = "/io/github/MatthewAllDev/BUnlock";
+pub const INTERFACE: &str = "io.github.MatthewAllDev.BUnlock";
+const METHOD: &str = "RequestApproval";
+const BUS_DEST: &str = "org.freedesktop.DBus";
+const BUS_PATH: &str = "/org/freedesktop/DBus";
+
+/// Lets polkit agents and sudo helpers ask the daemon to approve an admin action by
+/// the presence of the trusted device, optionally confirmed with a click.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ApprovalSettings {
+    /// Ask for a click on a desktop notification once the device is found.
+    pub confirm: bool,
+    /// How long to look for the device.
+    pub presence_timeout_seconds: u64,
+    /// How long to wait for the click.
+    pub confirm_timeout_seconds: u64,
+}
+
+impl Default for ApprovalSettings {
+    fn default() -> Self {
+        ApprovalSettings {
+            confirm: true,
+            presence_timeout_seconds: 5,
+            confirm_timeout_seconds: 15,
+        }
+    }
+}
+
+impl ApprovalSettings {
+    /// The longest a request can take, for callers to set their D-Bus timeout.
+    pub fn max_duration(&self) -> Duration {
+        Duration::from_secs(self.presence_timeout_seconds + self.confirm_timeout_seconds)
+    }
+}
+
+pub struct ApprovalRequest {
+    pub action: String,
+    pub message: String,
+    pub requester: String,
+    reply: mpsc::Sender<(bool, String)>,
+}
+
+/// Serves `RequestApproval(action, message) -> (allowed, reason)` on the session
+/// bus and forwards each call to the returned channel. The calling D-Bus thread
+/// waits for the answer, so requests are handled one at a time.
+pub fn serve() -> Result<UnboundedReceiver<ApprovalRequest>, Box<dyn Error>> {
+    let connection = Connection::new_session()?;
+    connection.request_name(BUS_NAME, false, true, true)?;
+    let (sender, receiver) = unbounded_channel();
+    let mut crossroads = Crossroads::new();
+    let token = crossroads.register(INTERFACE, |builder| {
+        builder.method(
+            METHOD,
+            ("action", "message"),
+            ("allowed", "reason"),
+            |ctx, requests: &mut UnboundedSender<ApprovalRequest>, (action, message): (String, String)| {
+                let (reply, answer) = mpsc::channel();
+                let requester = ctx.message().sender().map(|s| s.to_string()).unwrap_or_default();
+                requests
+                    .send(ApprovalRequest { action, message, requester, reply })
+                    .map_err(|_| MethodErr::failed("The daemon is shutting down"))?;
+                answer.recv().map_err(|_| MethodErr::failed("The request was dropped"))
+            },
+        );
+    });
+    crossroads.insert(OBJECT_PATH, &[token], sender);
+    debug!("Serving {} on the session bus", BUS_NAME);
+    std::thread::spawn(move || {
+        if let Err(e) = crossroads.serve(&connection) {
+            error!("Approval service stopped: {}", e);
+        }
+    });
+    Ok(receiver)
+}
+
+/// Handles the request on its own thread, so that the daemon keeps ticking during
+/// the presence check and the confirmation.
+pub fn spawn_handler(request: ApprovalRequest, config: Config) {
+    std::thread::spawn(move || {
+        match tokio::runtime::Builder::new_current_thread().enable_all().build() {
+            Ok(runtime) => runtime.block_on(handle(request, config)),
+            Err(e) => error!("Failed to handle the approval request: {}", e),
+        }
+    });
+}
+
+/// Decides a request, records it in the audit log and answers the caller.
+async fn handle(request: ApprovalRequest, config: Config) {
+    let caller = caller(&request.requester).map_err(|e| e.to_string());
+    let (allowed, reason) = decide(&request, &caller, &config).await;
+    let decision = if allowed { "allow" } else { "deny" };
+    info!(
+        bunlock_event = "approval",
+        bunlock_device = config.device.id.as_str(),
+        bunlock_decision = decision,
+        bunlock_reason = reason.as_str();
+        "Approval of \"{}\" requested by {}: {} ({})", request.action, request.requester, decision, reason
+    );
+    let record = audit::Record {
+        action: Some(request.action.clone()),
+        requester: Some(request.requester.clone()),
+        requester_uid: caller.as_ref().ok().map(|caller| caller.uid),
+        requester_pid: caller.as_ref().ok().map(|caller| caller.pid),
+        ..audit::Record::new("approval", &config.device.id, decision, &reason)
+    };
+    if let Err(e) = audit::append(&audit::audit_log_path(), &record) {
+        error!("Failed to write the audit log: {}", e);
+    }
+    request.reply.send((allowed, reason)).ok();
+}
+
+/// Only the user's own programs and root's (polkit, sudo helpers) may ask.
+async fn decide(request: &ApprovalRequest, caller: &Result<Caller, String>, config: &Config) -> (bool, String) {
+    let Some(settings) = &config.approval else {
+        return (false, "approval is not enabled".to_string());
+    };
+    match caller {
+        Ok(caller) if caller.uid == 0 || caller.uid == unsafe { libc::getuid() } => {}
+        Ok(caller) => return (false, format!("requested by another user (uid {})", caller.uid)),
+        Err(e) => return (false, format!("caller unknown ({})", e)),
+    }
+    let timeout = Duration::from_secs(settings.presence_timeout_seconds);
+    match presence::check(config, timeout).await {
+        Ok(true) => {}
+        Ok(false) => return (false, "device not in range".to_string()),
+        Err(e) => return (false, format!("presence check failed ({})", e)),
+    }
+    if !settings.confirm {
+        return (true, "device in range".to_string());
+    }
+    let summary = format!("Allow {}?", request.action);
+    let body = request.message.clone();
+    let timeout = Duration::from_secs(settings.confirm_timeout_seconds);
+    let confirmed = tokio::task::spawn_blocking(move || {
+        notifications::confirm(&summary, &body, timeout).map_err(|e| e.to_string())
+    });
+    match confirmed.await {
+        Ok(Ok(true)) => (true, "device in range, confirmed".to_string()),
+        Ok(Ok(false)) => (false, "not confirmed".to_string()),
+        Ok(Err(e)) => (false, format!("confirmation failed ({})", e)),
+        Err(e) => (false, format!("confirmation failed ({})", e)),
+    }
+}
+
+/// The process behind a unique bus name.
+struct Caller {
+    uid: u32,
+    pid: u32,
+}
+
+fn caller(sender: &str) -> Result<Caller, Box<dyn Error>> {
+    let connection = Connection::new_session()?;
+    let proxy = connection.with_proxy(BUS_DEST, BUS_PATH, Duration::from_secs(2));
+    let (uid,): (u32,) = proxy.method_call(BUS_DEST, "GetConnectionUnixUser", (sender,))?;
+    let (pid,): (u32,) = proxy.method_call(BUS_DEST, "GetConnectionUnixProcessID", (sender,))?;
+    Ok(Caller { uid, pid })
+}
+
+/// Calls `RequestApproval` on the running daemon.
+pub fn request(action: &str, message: &str, timeout: Duration) -> Result<(bool, String), Box<dyn Error>> {
+    let connection = Connection::new_session()?;
+    let proxy = connection.with_proxy(BUS_NAME, OBJECT_PATH, timeout);
+    let answer: (bool, String) = proxy.method_call(INTERFACE, METHOD, (action, message))?;
+    Ok(answer)
+}
@@ -0,0 +1,355 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::fs::{create_dir_all, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use crate::unlocker::bluetooth::device::Device;
+use crate::unlocker::config::Config;
+use crate::unlocker::paths;
+
+const AUDIT_FILE: &str = "audit.log";
+/// The log is rotated once it grows beyond this size.
+const MAX_SIZE: u64 = 1024 * 1024;
+/// Number of rotated files kept next to the current one (`audit.log.1` is the newest).
+const ROTATED_FILES: u32 = 3;
+
+/// One line of the audit log.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Record {
+    pub time: DateTime<Local>,
+    /// `unlock` for the daemon's unlock decisions, `approval` for approval requests.
+    pub event: String,
+    pub device: String,
+    pub decision: String,
+    pub reason: String,
+    /// Raw RSSI reading (dBm), absent when the device wasn't seen.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rssi: Option<i16>,
+    /// RSSI threshold (dBm) in effect.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threshold: Option<i16>,
+    /// Estimated distance (m), when the device advertises its TX power.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub distance_metres: Option<f64>,
+    /// Distance threshold (m) in effect, which takes precedence over the RSSI threshold.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_distance_metres: Option<f64>,
+    /// Lock status backend.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub backend: Option<String>,
+    /// The admin action an approval was requested for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub action: Option<String>,
+    /// D-Bus name of the program that requested an approval.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requester: Option<String>,
+    /// Uid of the requesting process.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requester_uid: Option<u32>,
+    /// Pid of the requesting process.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requester_pid: Option<u32>,
+}
+
+impl Record {
+    pub fn new(event: &str, device: &str, decision: &str, reason: &str) -> Record {
+        Record {
+            time: Local::now(),
+            event: event.to_string(),
+            device: device.to_string(),
+            decision: decision.to_string(),
+            reason: reason.to_string(),
+            rssi: None,
+            threshold: None,
+            distance_metres: None,
+            max_distance_metres: None,
+            backend: None,
+            action: None,
+            requester: None,
+            requester_uid: None,
+            requester_pid: None,
+        }
+    }
+
+    /// An unlock decision with the readings it was based on.
+    pub fn unlock(config: &Config, device: &Device, decision: &str, reason: &str) -> Record {
+        Record {
+            rssi: (device.rssi != i16::MIN).then_some(device.rssi),
+            threshold: Some(config.distance),
+            distance_metres: device.estimated_distance(config.path_loss_exponent),
+            max_distance_metres: config.distance_metres,
+            ..Record::new("unlock", &device.id, decision, reason)
+        }
+    }
+}
+
+impl fmt::Display for Record {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}  {:<8}  {:<6}  {}",
+            self.time.format("%Y-%m-%d %H:%M:%S"),
+            self.event,
+            self.decision,
+            self.device
+        )?;
+        if let Some(rssi) = self.rssi {
+            write!(f, "  RSSI {}", rssi)?;
+            if let Some(threshold) = self.threshold {
+                write!(f, "/{}", threshold)?;
+            }
+        }
+        if let Some(distance) = self.distance_metres {
+            write!(f, "  {:.1} m", distance)?;
+            if let Some(max_distance) = self.max_distance_metres {
+                write!(f, "/{:.1} m", max_distance)?;
+            }
+        }
+        if let Some(action) = &self.action {
+            write!(f, "  \"{}\"", action)?;
+        }
+        if let Some(requester) = &self.requester {
+            write!(f, " by {}", requester)?;
+            if let (Some(uid), Some(pid)) = (self.requester_uid, self.requester_pid) {
+                write!(f, " (uid {}, pid {})", uid, pid)?;
+            }
+        }
+        write!(f, "  {}", self.reason)
+    }
+}
+
+pub fn audit_log_path() -> PathBuf {
+    paths::state_dir().join(AUDIT_FILE)
+}
+
+/// Like [`audit_log_path`], for another user's log.
+pub fn audit_log_path_in(home: &Path) -> PathBuf {
+    paths::state_dir_in(home).join(AUDIT_FILE)
+}
+
+/// Appends the record as a JSON line, rotating the file first if it is too big. The
+/// files are only readable by the user.
+pub fn append(path: &Path, record: &Record) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    if path.metadata().is_ok_and(|metadata| metadata.len() >= MAX_SIZE) {
+        rotate(path)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).mode(0o600).open(path)?;
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// Reads the records of the current and rotated files, oldest first. Lines that
+/// can't be parsed are skipped.
+fn read_all(path: &Path) -> Result<Vec<Record>, Box<dyn Error>> {
+    let mut records = vec![];
+    for index in (0..=ROTATED_FILES).rev() {
+        let file = match std::fs::File::open(rotated_path(path, index)) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for line in BufReader::new(file).lines() {
+            if let Ok(record) = serde_json::from_str(&line?) {
+                records.push(record);
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// Which records `bunlock history` shows.
+#[derive(Debug, Default)]
+pub struct Filter {
+    pub event: Option<String>,
+    pub decision: Option<String>,
+    pub since: Option<DateTime<Local>>,
+    /// Only the newest this many records.
+    pub limit: Option<usize>,
+}
+
+impl Filter {
+    fn matches(&self, record: &Record) -> bool {
+        self.event.as_ref().is_none_or(|event| *event == record.event)
+            && self.decision.as_ref().is_none_or(|decision| *decision == record.decision)
+            && self.since.is_none_or(|since| record.time >= since)
+    }
+}
+
+/// How long ago the unlocks of the last `within` were recorded.
+pub fn recent_unlocks(path: &Path, within: Duration) -> Result<Vec<Duration>, Box<dyn Error>> {
+    let now = Local::now();
+    let filter = Filter {
+        event: Some("unlock".to_string()),
+        decision: Some("unlock".to_string()),
+        since: Some(now - within),
+        limit: None,
+    };
+    let records = history(path, &filter)?;
+    Ok(records.into_iter().filter_map(|record| (now - record.time).to_std().ok()).collect())
+}
+
+/// The matching records, oldest first.
+pub fn history(path: &Path, filter: &Filter) -> Result<Vec<Record>, Box<dyn Error>> {
+    let mut records: Vec<Record> = read_all(path)?.into_iter().filter(|r| filter.matches(r)).collect();
+    if let Some(limit) = filter.limit {
+        records.drain(..records.len().saturating_sub(limit));
+    }
+    Ok(records)
+}
+
+/// Parses `YYYY-MM-DD` or `YYYY-MM-DD HH:MM` as local time.
+pub fn parse_time(input: &str) -> Result<DateTime<Local>, String> {
+    let naive = NaiveDateTime::parse_from_str(input, "%Y-%m-%d %H:%M")
+        .or_else(|_| NaiveDate::parse_from_str(input, "%Y-%m-%d").map(|date| date.and_hms_opt(0, 0, 0).unwrap()))
+        .map_err(|_| format!("Invalid time {}, expected YYYY-MM-DD or YYYY-MM-DD HH:MM", input))?;
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .ok_or(format!("Time {} doesn't exist in the local time zone", input))
+}
+
+fn rotate(path: &Path) -> Result<(), Box<dyn Error>> {
+    for index in (1..ROTATED_FILES).rev() {
+        let from = rotated_path(path, index);
+        if from.exists() {
+            std::fs::rename(&from, rotated_path(path, index + 1))?;
+        }
+    }
+    std::fs::rename(path, rotated_path(path, 1))?;
+    Ok(())
+}
+
+/// `audit.log` for 0, `audit.log.<index>` otherwise.
+fn rotated_path(path: &Path, index: u32) -> PathBuf {
+    if index == 0 {
+        return path.to_path_buf();
+    }
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", index));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use std::os::unix::fs::PermissionsExt;
+
+    /// A fresh directory per test, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let dir = std::env::temp_dir().join(format!("bunlock-audit-{}-{}", std::process::id(), name));
+            std::fs::remove_dir_all(&dir).ok();
+            TempDir(dir)
+        }
+
+        fn log(&self) -> PathBuf {
+            self.0.join(AUDIT_FILE)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            std::fs::remove_dir_all(&self.0).ok();
+        }
+    }
+
+    fn record(decision: &str, minutes_ago: i64) -> Record {
+        Record {
+            time: Local::now() - Duration::minutes(minutes_ago),
+            ..Record::new("unlock", "AA:BB:CC:DD:EE:FF", decision, "test")
+        }
+    }
+
+    #[test]
+    fn appends_records_readable_by_the_user_only() {
+        let dir = TempDir::new("append");
+        append(&dir.log(), &record("unlock", 2)).unwrap();
+        append(&dir.log(), &record("deny", 1)).unwrap();
+        let records = history(&dir.log(), &Filter::default()).unwrap();
+        let decisions: Vec<&str> = records.iter().map(|r| r.decision.as_str()).collect();
+        assert_eq!(decisions, ["unlock", "deny"]);
+        assert_eq!(dir.log().metadata().unwrap().permissions().mode() & 0o777, 0o600);
+    }
+
+    #[test]
+    fn rotates_large_files() {
+        let dir = TempDir::new("rotate");
+        append(&dir.log(), &record("unlock", 3)).unwrap();
+        for minutes_ago in [2, 1, 0] {
+            OpenOptions::new().append(true).open(dir.log()).unwrap().set_len(MAX_SIZE).unwrap();
+            append(&dir.log(), &record("deny", minutes_ago)).unwrap();
+        }
+        assert!(rotated_path(&dir.log(), 3).exists());
+        assert!(!rotated_path(&dir.log(), 4).exists());
+        // The padding isn't JSON and is skipped; the oldest file is still read first.
+        let records = history(&dir.log(), &Filter::default()).unwrap();
+        let decisions: Vec<&str> = records.iter().map(|r| r.decision.as_str()).collect();
+        assert_eq!(decisions, ["unlock", "deny", "deny", "deny"]);
+        // One more rotation drops the oldest file.
+        OpenOptions::new().append(true).open(dir.log()).unwrap().set_len(MAX_SIZE).unwrap();
+        append(&dir.log(), &record("error", 0)).unwrap();
+        let records = history(&dir.log(), &Filter::default()).unwrap();
+        let decisions: Vec<&str> = records.iter().map(|r| r.decision.as_str()).collect();
+        assert_eq!(decisions, ["deny", "deny", "deny", "error"]);
+    }
+
+    #[test]
+    fn filters_records() {
+        let dir = TempDir::new("filter");
+        for (decision, minutes_ago) in [("unlock", 50), ("deny", 40), ("unlock", 30), ("deny", 20), ("deny", 10)] {
+            append(&dir.log(), &record(decision, minutes_ago)).unwrap();
+        }
+        let count = |filter: Filter| history(&dir.log(), &filter).unwrap().len();
+        assert_eq!(count(Filter { decision: Some("deny".to_string()), ..Filter::default() }), 3);
+        assert_eq!(count(Filter { event: Some("approval".to_string()), ..Filter::default() }), 0);
+        assert_eq!(count(Filter { since: Some(Local::now() - Duration::minutes(35)), ..Filter::default() }), 3);
+        let newest = history(&dir.log(), &Filter { limit: Some(2), ..Filter::default() }).unwrap();
+        assert_eq!(newest.len(), 2);
+        assert!(newest[0].time < newest[1].time);
+        assert_eq!(newest[1].time, history(&dir.log(), &Filter::default()).unwrap()[4].time);
+    }
+
+    #[test]
+    fn lists_recent_unlocks() {
+        let dir = TempDir::new("unlocks");
+        for (decision, minutes_ago) in [("unlock", 90), ("unlock", 30), ("deny", 20), ("unlock", 10)] {
+            append(&dir.log(), &record(decision, minutes_ago)).unwrap();
+        }
+        let minutes: Vec<u64> = recent_unlocks(&dir.log(), std::time::Duration::from_secs(3600))
+            .unwrap()
+            .iter()
+            .map(|age| (age.as_secs() + 30) / 60)
+            .collect();
+        assert_eq!(minutes, [30, 10]);
+    }
+
+    #[test]
+    fn parses_times() {
+        let time = parse_time("2024-03-01 08:30").unwrap();
+        assert_eq!(time.format("%Y-%m-%d %H:%M").to_string(), "2024-03-01 08:30");
+        let date = parse_time("2024-03-01").unwrap();
+        assert_eq!(date.format("%H:%M").to_string(), "00:00");
+        assert!(parse_time("01.03.2024").is_err());
+    }
+}
@@ -0,0 +1,200 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+use btleplug::api::PeripheralProperties;
+use std::collections::HashMap;
+use std::fmt;
+use uuid::Uuid;
+use crate::unlocker::bluetooth::match_rule::MatchRule;
+
+const APPLE_COMPANY_ID: u16 = 0x004C;
+const IBEACON_PREFIX: [u8; 2] = [0x02, 0x15];
+pub const EDDYSTONE_SERVICE_UUID: Uuid = Uuid::from_u128(0x0000feaa_0000_1000_8000_00805f9b34fb);
+const EDDYSTONE_UID_FRAME: u8 = 0x00;
+/// Free-space path loss at 1 m for 2.4 GHz. Advertised TX power and Eddystone's
+/// calibrated power are measured at 0 m, iBeacon's at 1 m; this is the loss in between.
+pub const PATH_LOSS_AT_ONE_METRE: i16 = 41;
+
+/// An iBeacon frame from Apple's manufacturer data.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IBeacon {
+    pub uuid: Uuid,
+    pub major: u16,
+    pub minor: u16,
+    /// Calibrated RSSI at 1 m.
+    pub measured_power: i8,
+}
+
+impl IBeacon {
+    pub fn parse(manufacturer_data: &HashMap<u16, Vec<u8>>) -> Option<IBeacon> {
+        let data = manufacturer_data.get(&APPLE_COMPANY_ID)?;
+        if data.len() < 23 || data[..2] != IBEACON_PREFIX {
+            return None;
+        }
+        Some(IBeacon {
+            uuid: Uuid::from_slice(&data[2..18]).ok()?,
+            major: u16::from_be_bytes([data[18], data[19]]),
+            minor: u16::from_be_bytes([data[20], data[21]]),
+            measured_power: data[22] as i8,
+        })
+    }
+}
+
+/// An Eddystone-UID frame from the Eddystone service data.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EddystoneUid {
+    pub namespace: [u8; 10],
+    pub instance: [u8; 6],
+    /// Calibrated TX power at 0 m.
+    pub tx_power: i8,
+}
+
+impl EddystoneUid {
+    pub fn parse(service_data: &HashMap<Uuid, Vec<u8>>) -> Option<EddystoneUid> {
+        let frame = service_data.get(&EDDYSTONE_SERVICE_UUID)?;
+        if frame.len() < 18 || frame[0] != EDDYSTONE_UID_FRAME {
+            return None;
+        }
+        Some(EddystoneUid {
+            namespace: frame[2..12].try_into().ok()?,
+            instance: frame[12..18].try_into().ok()?,
+            tx_power: frame[1] as i8,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Beacon {
+    IBeacon(IBeacon),
+    EddystoneUid(EddystoneUid),
+}
+
+impl Beacon {
+    pub fn from_properties(properties: &PeripheralProperties) -> Option<Beacon> {
+        IBeacon::parse(&properties.manufacturer_data)
+            .map(Beacon::IBeacon)
+            .or_else(|| EddystoneUid::parse(&properties.service_data).map(Beacon::EddystoneUid))
+    }
+
+    /// Expected RSSI at 1 m, as calibrated by the beacon.
+    pub fn reference_rssi(&self) -> i16 {
+        match self {
+            Beacon::IBeacon(beacon) => i16::from(beacon.measured_power),
+            Beacon::EddystoneUid(beacon) => i16::from(beacon.tx_power) - PATH_LOSS_AT_ONE_METRE,
+        }
+    }
+
+    /// A rule that recognises exactly this beacon.
+    pub fn match_rule(&self) -> MatchRule {
+        match self {
+            Beacon::IBeacon(beacon) => MatchRule::IBeacon {
+                uuid: beacon.uuid,
+                major: Some(beacon.major),
+                minor: Some(beacon.minor),
+            },
+            Beacon::EddystoneUid(beacon) => MatchRule::Eddystone {
+                namespace: to_hex(&beacon.namespace),
+                instance: Some(to_hex(&beacon.instance)),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Beacon {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Beacon::IBeacon(beacon) => write!(
+                f,
+                "iBeacon {} major {} minor {}",
+                beacon.uuid, beacon.major, beacon.minor
+            ),
+            Beacon::EddystoneUid(beacon) => write!(
+                f,
+                "Eddystone {} instance {}",
+                to_hex(&beacon.namespace),
+                to_hex(&beacon.instance)
+            ),
+        }
+    }
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ibeacon_data() -> Vec<u8> {
+        let mut data = IBEACON_PREFIX.to_vec();
+        data.extend_from_slice(Uuid::from_u128(0x0102030405060708090a0b0c0d0e0f10).as_bytes());
+        data.extend_from_slice(&[0x00, 0x2a, 0x01, 0x00, 0xc5]);
+        data
+    }
+
+    fn eddystone_frame() -> Vec<u8> {
+        let mut frame = vec![EDDYSTONE_UID_FRAME, 0xee];
+        frame.extend_from_slice(&[0x11; 10]);
+        frame.extend_from_slice(&[0x22; 6]);
+        frame
+    }
+
+    #[test]
+    fn parses_ibeacon() {
+        let data = HashMap::from([(APPLE_COMPANY_ID, ibeacon_data())]);
+        let beacon = IBeacon::parse(&data).unwrap();
+        assert_eq!(beacon.uuid, Uuid::from_u128(0x0102030405060708090a0b0c0d0e0f10));
+        assert_eq!(beacon.major, 42);
+        assert_eq!(beacon.minor, 256);
+        assert_eq!(beacon.measured_power, -59);
+        assert_eq!(Beacon::IBeacon(beacon).reference_rssi(), -59);
+    }
+
+    #[test]
+    fn rejects_other_apple_data() {
+        let mut data = ibeacon_data();
+        data[0] = 0x10;
+        assert_eq!(IBeacon::parse(&HashMap::from([(APPLE_COMPANY_ID, data)])), None);
+        let mut short = ibeacon_data();
+        short.pop();
+        assert_eq!(IBeacon::parse(&HashMap::from([(APPLE_COMPANY_ID, short)])), None);
+        assert_eq!(IBeacon::parse(&HashMap::from([(0x0075, ibeacon_data())])), None);
+    }
+
+    #[test]
+    fn parses_eddystone_uid() {
+        let data = HashMap::from([(EDDYSTONE_SERVICE_UUID, eddystone_frame())]);
+        let beacon = EddystoneUid::parse(&data).unwrap();
+        assert_eq!(beacon.namespace, [0x11; 10]);
+        assert_eq!(beacon.instance, [0x22; 6]);
+        assert_eq!(beacon.tx_power, -18);
+        assert_eq!(Beacon::EddystoneUid(beacon).reference_rssi(), -18 - PATH_LOSS_AT_ONE_METRE);
+    }
+
+    #[test]
+    fn rejects_other_eddystone_frames() {
+        let mut url = eddystone_frame();
+        url[0] = 0x10;
+        assert_eq!(EddystoneUid::parse(&HashMap::from([(EDDYSTONE_SERVICE_UUID, url)])), None);
+        let short = eddystone_frame()[..17].to_vec();
+        assert_eq!(EddystoneUid::parse(&HashMap::from([(EDDYSTONE_SERVICE_UUID, short)])), None);
+    }
+
+    #[test]
+    fn match_rule_recognises_the_beacon() {
+        let properties = PeripheralProperties {
+            service_data: HashMap::from([(EDDYSTONE_SERVICE_UUID, eddystone_frame())]),
+            ..PeripheralProperties::default()
+        };
+        let beacon = Beacon::from_properties(&properties).unwrap();
+        assert!(beacon.match_rule().matches(&properties));
+        assert_eq!(beacon.to_string(), "Eddystone 11111111111111111111 instance 222222222222");
+    }
+}
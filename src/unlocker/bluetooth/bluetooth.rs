@@ -13,14 +13,21 @@ use tokio::time::{sleep, Duration};
 pub use btleplug::platform::{Manager, Adapter};
 use log::{info, error};
 
+/// How discovery is run on the adapter.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScanOptions {
+    /// Only report devices advertising one of these services.
+    pub filter: ScanFilter,
+}
+
 pub async fn get_adapter()-> Result<Adapter, Box<dyn Error>> {
     let manager = Manager::new().await?;
     let adapters = manager.adapters().await?;
-    let central = adapters.into_iter().nth(0).ok_or("No adapters found")?;
+    let central = adapters.into_iter().next().ok_or("No adapters found")?;
     Ok(central)
 }
 
-pub async fn start_scan(adapter: Option<Adapter>, wait_for_adapter: bool) -> Result<Adapter, Box<dyn Error>> {
+pub async fn start_scan(adapter: Option<Adapter>, options: &ScanOptions, wait_for_adapter: bool) -> Result<Adapter, Box<dyn Error>> {
     let adapter = match adapter {
         Some(ad) => ad,
         None => {
@@ -28,7 +35,7 @@ pub async fn start_scan(adapter: Option<Adapter>, wait_for_adapter: bool) -> Res
         }
     };
     loop {
-        match adapter.start_scan(ScanFilter::default()).await {
+        match adapter.start_scan(options.filter.clone()).await {
             Ok(_) => return Ok(adapter),
             Err(e) => {
                 if wait_for_adapter {
@@ -41,4 +48,9 @@ pub async fn start_scan(adapter: Option<Adapter>, wait_for_adapter: bool) -> Res
             }
         }
     }
-}
\ No newline at end of file
+}
+
+pub async fn stop_scan(adapter: &Adapter) -> Result<(), Box<dyn Error>> {
+    adapter.stop_scan().await?;
+    Ok(())
+}
@@ -7,18 +7,22 @@
  * See the LICENSE file for details.
  */
 
-use btleplug::api::{Central, Peripheral as _};
+use btleplug::api::{Central, CentralState, Peripheral as _, PeripheralProperties, ScanFilter};
 use btleplug::platform::Peripheral;
 use log::{debug, error};
 use serde::{self, Deserialize, Deserializer, Serialize, Serializer};
 use std::fmt;
-use std::{error::Error, i16};
+use std::error::Error;
 use tokio::signal::unix::{signal, SignalKind};
-use tokio::time::{sleep, Duration};
-use crate::unlocker::bluetooth::bluetooth;
+use tokio::time::{sleep, timeout, Duration, Instant};
+use crate::unlocker::bluetooth::beacon::{Beacon, PATH_LOSS_AT_ONE_METRE};
+use crate::unlocker::bluetooth::bluetooth::{self, ScanOptions};
+use crate::unlocker::bluetooth::match_rule::MatchRule;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub async fn get_all() -> Result<Vec<Device>, Box<dyn Error>> {
-    let adapter = bluetooth::start_scan(None, false).await?;
+    let adapter = bluetooth::start_scan(None, &ScanOptions::default(), false).await?;
     let mut devices: Vec<Device> = vec![];
     for peripheral in adapter.peripherals().await? {
         let d = Device::from_peripheral(peripheral)
@@ -26,16 +30,59 @@ pub async fn get_all() -> Result<Vec<Device>, Box<dyn Error>> {
             .unwrap_or_default();
         devices.push(d);
     }
-    devices.sort_by(|a, b| b.rssi.cmp(&a.rssi));
+    devices.sort_by_key(|device| std::cmp::Reverse(device.rssi));
     Ok(devices)
 }
 
+#[derive(Clone)]
+struct Backoff {
+    attempt_count: u32,
+    max_attempts_before_delay: u32,
+    current_delay: Duration,
+}
+
+impl Backoff {
+    const SHORT_DELAY: Duration = Duration::from_millis(1000);
+    const LONG_DELAY: Duration = Duration::from_secs(5);
+
+    fn new() -> Backoff {
+        Backoff {
+            attempt_count: 0,
+            max_attempts_before_delay: 10,
+            current_delay: Backoff::SHORT_DELAY,
+        }
+    }
+
+    fn failed(&mut self) -> Duration {
+        self.attempt_count += 1;
+        if self.attempt_count >= self.max_attempts_before_delay && self.current_delay != Backoff::LONG_DELAY {
+            self.current_delay = Backoff::LONG_DELAY;
+            debug!("Increasing delay to {:?}", self.current_delay);
+        }
+        self.current_delay
+    }
+
+    fn reset(&mut self) {
+        *self = Backoff::new();
+    }
+}
+
 #[derive(Clone)]
 pub struct Device {
     peripheral: Option<Peripheral>,
+    adapter: Option<bluetooth::Adapter>,
+    pub scan_options: ScanOptions,
+    reconnect: Backoff,
+    next_connect_attempt: Option<Instant>,
     pub id: String,
     pub name: String,
     pub rssi: i16,
+    pub tx_power: Option<i16>,
+    pub beacon: Option<Beacon>,
+    /// Whether the last reading came from a live GATT connection.
+    pub connected: bool,
+    /// Advertisement rules that identify the device in addition to its address.
+    pub match_rules: Vec<MatchRule>,
 }
 
 impl Device {
@@ -48,70 +95,102 @@ impl Device {
         let rssi = rssi.unwrap_or(i16::MIN);
         Ok(Device {
             peripheral,
+            adapter: None,
+            scan_options: ScanOptions::default(),
+            reconnect: Backoff::new(),
+            next_connect_attempt: None,
             id,
             name,
             rssi,
+            tx_power: None,
+            beacon: None,
+            connected: false,
+            match_rules: vec![],
         })
     }
 
     pub async fn update_peripheral(&mut self) -> Result<(), Box<dyn Error>> {
         debug!("Searching for peripheral with id: {}", self.id);
-        let adapter = bluetooth::start_scan(None, true).await?;
-        let mut attempt_count = 0;
-        let max_attempts_before_delay = 10;
-        let mut current_delay = Duration::from_millis(1000);
+        let adapter = bluetooth::start_scan(self.adapter.clone(), &self.scan_options, true).await?;
+        self.adapter = Some(adapter.clone());
+        let mut backoff = Backoff::new();
         let mut sigterm = signal(SignalKind::terminate())?;
         let mut sigint = signal(SignalKind::interrupt())?;
-        let mut should_stop = false;
-    
+
         loop {
             tokio::select! {
                 _ = sigterm.recv() => {
                     debug!("Received SIGTERM, shutting down...");
-                    should_stop = true;
                     return Ok(());
                 }
                 _ = sigint.recv() => {
                     debug!("Received SIGINT, shutting down...");
-                    should_stop = true;
                     return Ok(());
                 }
-                result = async {
-                    match self.search_peripheral(&adapter).await {
+                result = self.search_peripheral(&adapter) => {
+                    match result {
                         Ok(Some(peripheral)) => {
                             self.peripheral = Some(peripheral);
                             debug!("Peripheral found and set.");
-                            should_stop = true;
                             return Ok(());
                         }
                         Ok(None) => {
-                            debug!("Peripheral not found, attempt {}/{}", attempt_count + 1, max_attempts_before_delay);
-                            attempt_count += 1;
-                            if attempt_count >= max_attempts_before_delay {
-                                current_delay = Duration::from_secs(5);
-                                debug!("Increasing delay to {:?}", current_delay);
-                            }
-                            sleep(current_delay).await;
-                            Ok(())
+                            debug!(
+                                "Peripheral not found, attempt {}/{}",
+                                backoff.attempt_count + 1,
+                                backoff.max_attempts_before_delay
+                            );
+                            sleep(backoff.failed()).await;
                         }
                         Err(e) => {
-                            error!("Error searching for peripheral: {}", e);
-                            sleep(current_delay).await;
-                            Err(e)
+                            error!("Error during peripheral update: {}", e);
+                            sleep(backoff.current_delay).await;
                         }
                     }
-                } => {
-                    if let Err(e) = result {
-                        error!("Error during peripheral update: {}", e);
-                    }
                 }
             }
-            if should_stop {
-                return Ok(());
+        }
+    }
+
+    pub fn is_found(&self) -> bool {
+        self.peripheral.is_some()
+    }
+
+    /// Checks that the adapter is still there and powered on. A lost adapter is looked
+    /// up again on the next check, in case it came back as a new one.
+    pub async fn check_adapter(&mut self) -> Result<(), Box<dyn Error>> {
+        let adapter = match &self.adapter {
+            Some(adapter) => adapter.clone(),
+            None => bluetooth::get_adapter().await?,
+        };
+        match adapter.adapter_state().await {
+            Ok(CentralState::PoweredOn) => {
+                self.adapter = Some(adapter);
+                Ok(())
+            }
+            Ok(_) => {
+                self.adapter = None;
+                Err("adapter is powered off or gone".into())
+            }
+            Err(e) => {
+                self.adapter = None;
+                Err(e.into())
             }
         }
     }
 
+    /// Looks for the peripheral once among those already discovered, without waiting
+    /// for it to appear like [`Device::update_peripheral`]. Returns whether it was found.
+    pub async fn search_once(&mut self) -> Result<bool, Box<dyn Error>> {
+        let adapter = match &self.adapter {
+            Some(adapter) => adapter.clone(),
+            None => bluetooth::get_adapter().await?,
+        };
+        self.adapter = Some(adapter.clone());
+        self.peripheral = self.search_peripheral(&adapter).await?;
+        Ok(self.peripheral.is_some())
+    }
+
     async fn search_peripheral(
         &self,
         adapter: &bluetooth::Adapter,
@@ -122,8 +201,17 @@ impl Device {
                     if self.id == peripheral.address().to_string() {
                         return Ok(Some(peripheral));
                     }
+                    if self.match_rules.is_empty() {
+                        continue;
+                    }
+                    if let Ok(Some(properties)) = peripheral.properties().await {
+                        if let Some(rule) = self.match_rules.iter().find(|r| r.matches(&properties)) {
+                            debug!("Device {} matched by {} at {}", self, rule, properties.address);
+                            return Ok(Some(peripheral));
+                        }
+                    }
                 }
-                debug!("Device not found: {}", self.to_string());
+                debug!("Device not found: {}", self);
             }
             Err(e) => {
                 error!("Failed to get peripherals: {}", e);
@@ -135,15 +223,139 @@ impl Device {
     pub async fn update_rssi(&mut self) -> i16 {
         let rssi: i16 = if let Some(peripheral) = &self.peripheral {
             match peripheral.properties().await {
-                Ok(properties) => properties.and_then(|p| p.rssi).unwrap_or(i16::MIN),
-                Err(_) => i16::MIN,
+                Ok(Some(properties)) => {
+                    self.apply_properties(&properties);
+                    properties.rssi.unwrap_or(i16::MIN)
+                }
+                Ok(None) => i16::MIN,
+                Err(_) => {
+                    if !self.match_rules.is_empty() {
+                        // The address may have rotated, so look for the device again.
+                        self.peripheral = None;
+                    }
+                    i16::MIN
+                }
             }
         } else {
             self.update_peripheral().await.ok();
             i16::MIN
         };
         self.rssi = rssi;
-        return rssi;
+        self.connected = false;
+        rssi
+    }
+
+    /// Reads the presence signal from a GATT connection instead of advertisements.
+    /// The connection is (re)established on demand, retrying with the same backoff
+    /// as the peripheral search; while disconnected the device is reported as absent.
+    /// BlueZ usually has no RSSI reading for a connected device; whether it then
+    /// counts as present is up to [`Config::is_in_range`](crate::unlocker::config::Config::is_in_range).
+    pub async fn update_connection_rssi(&mut self) -> i16 {
+        self.connected = false;
+        let Some(peripheral) = self.peripheral.clone() else {
+            self.update_peripheral().await.ok();
+            self.rssi = i16::MIN;
+            return self.rssi;
+        };
+        if !peripheral.is_connected().await.unwrap_or(false) && !self.connect(&peripheral).await {
+            self.rssi = i16::MIN;
+            return self.rssi;
+        }
+        self.connected = true;
+        let rssi = match peripheral.properties().await {
+            Ok(Some(properties)) => {
+                self.apply_properties(&properties);
+                properties.rssi.unwrap_or(i16::MIN)
+            }
+            _ => i16::MIN,
+        };
+        if rssi == i16::MIN {
+            debug!("No RSSI reading for connected {}", self);
+        }
+        self.rssi = rssi;
+        rssi
+    }
+
+    /// Starts or stops discovery on the adapter used to track this device.
+    pub async fn set_scanning(&mut self, active: bool) -> Result<(), Box<dyn Error>> {
+        if active {
+            let adapter = bluetooth::start_scan(self.adapter.clone(), &self.scan_options, false).await?;
+            self.adapter = Some(adapter);
+        } else if let Some(adapter) = &self.adapter {
+            bluetooth::stop_scan(adapter).await?;
+        }
+        Ok(())
+    }
+
+    /// A discovery filter for the services the device is known to advertise. Only
+    /// applies when every match rule implies a service; otherwise nothing is filtered,
+    /// since the device could be missed. With a filter the device is only found by
+    /// its rules, not by its address.
+    pub fn scan_filter(&self) -> ScanFilter {
+        let services: Option<Vec<_>> = self.match_rules.iter().map(|r| r.service_uuid()).collect();
+        match services {
+            Some(services) if !services.is_empty() => ScanFilter { services },
+            _ => ScanFilter::default(),
+        }
+    }
+
+    fn apply_properties(&mut self, properties: &PeripheralProperties) {
+        self.tx_power = properties.tx_power_level.or(self.tx_power);
+        if let Some(beacon) = Beacon::from_properties(properties) {
+            self.beacon = Some(beacon);
+        }
+    }
+
+    async fn connect(&mut self, peripheral: &Peripheral) -> bool {
+        if self.next_connect_attempt.is_some_and(|at| Instant::now() < at) {
+            return false;
+        }
+        let result = match timeout(CONNECT_TIMEOUT, peripheral.connect()).await {
+            Ok(result) => result.map_err(|e| e.to_string()),
+            Err(_) => Err("connection timed out".to_string()),
+        };
+        match result {
+            Ok(()) => {
+                debug!("Connected to {}", self);
+                self.reconnect.reset();
+                self.next_connect_attempt = None;
+                true
+            }
+            Err(e) => {
+                let delay = self.reconnect.failed();
+                debug!(
+                    "Failed to connect to {} ({}), attempt {}, retrying in {:?}",
+                    self, e, self.reconnect.attempt_count, delay
+                );
+                self.next_connect_attempt = Some(Instant::now() + delay);
+                false
+            }
+        }
+    }
+
+    /// Estimates the distance to the device in metres using the log-distance path loss
+    /// model. Returns `None` until both an RSSI reading and the advertised TX power
+    /// (or a beacon's calibrated power) are known.
+    pub fn estimated_distance(&self, path_loss_exponent: f64) -> Option<f64> {
+        if self.rssi == i16::MIN {
+            return None;
+        }
+        let reference_rssi = match &self.beacon {
+            Some(beacon) => beacon.reference_rssi(),
+            None => self.tx_power? - PATH_LOSS_AT_ONE_METRE,
+        };
+        let exponent = f64::from(reference_rssi - self.rssi) / (10.0 * path_loss_exponent);
+        Some(10f64.powf(exponent))
+    }
+
+    pub async fn disconnect(&self) {
+        if let Some(peripheral) = &self.peripheral {
+            if peripheral.is_connected().await.unwrap_or(false) {
+                if let Err(e) = peripheral.disconnect().await {
+                    error!("Failed to disconnect from {}: {}", self, e);
+                }
+            }
+        }
     }
 
     pub async fn from_peripheral(peripheral: Peripheral) -> Result<Device, Box<dyn Error>> {
@@ -154,11 +366,11 @@ impl Device {
             .as_ref()
             .and_then(|p| p.local_name.clone())
             .unwrap_or("(unknown)".to_string());
-        Device::new(Some(peripheral), address, name, rssi)
-    }
-
-    pub fn to_string(&self) -> String {
-        format!("{} ({})", self.name, self.id)
+        let mut device = Device::new(Some(peripheral), address, name, rssi)?;
+        if let Some(properties) = &properties {
+            device.apply_properties(properties);
+        }
+        Ok(device)
     }
 
     pub fn serialize<S>(device: &Device, serializer: S) -> Result<S::Ok, S::Error>
@@ -174,6 +386,10 @@ impl Device {
             "name".to_string(),
             serde_json::Value::String(device.name.clone()),
         );
+        if !device.match_rules.is_empty() {
+            let rules = serde_json::to_value(&device.match_rules).map_err(serde::ser::Error::custom)?;
+            map.insert("match_rules".to_string(), rules);
+        }
         map.serialize(serializer)
     }
 
@@ -185,19 +401,31 @@ impl Device {
         struct DeviceHelper {
             id: String,
             name: String,
+            #[serde(default)]
+            match_rules: Vec<MatchRule>,
         }
 
         let helper = DeviceHelper::deserialize(deserializer)?;
         let device = Device {
-            peripheral: None,
             id: helper.id,
             name: helper.name,
-            rssi: i16::MIN,
+            match_rules: helper.match_rules,
+            ..Device::default()
         };
         Ok(device)
     }
 }
 
+impl fmt::Display for Device {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.name, self.id)?;
+        if let Some(beacon) = &self.beacon {
+            write!(f, " [{}]", beacon)?;
+        }
+        Ok(())
+    }
+}
+
 impl fmt::Debug for Device {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{:?} ({:?})", self.name, self.id)
@@ -208,9 +436,61 @@ impl Default for Device {
     fn default() -> Self {
         Device {
             peripheral: None,
+            adapter: None,
+            scan_options: ScanOptions::default(),
+            reconnect: Backoff::new(),
+            next_connect_attempt: None,
             id: String::new(),
             name: String::from("(unknown)"),
             rssi: i16::MIN,
+            tx_power: None,
+            beacon: None,
+            connected: false,
+            match_rules: vec![],
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unlocker::bluetooth::beacon::IBeacon;
+
+    fn device(rssi: i16, tx_power: Option<i16>) -> Device {
+        Device { rssi, tx_power, ..Device::default() }
+    }
+
+    fn assert_close(actual: Option<f64>, expected: f64) {
+        let actual = actual.expect("a distance");
+        assert!((actual - expected).abs() < 0.01, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn estimates_the_distance_from_the_tx_power() {
+        // TX power 0 dBm means -41 dBm at 1 m.
+        assert_close(device(-41, Some(0)).estimated_distance(2.0), 1.0);
+        assert_close(device(-61, Some(0)).estimated_distance(2.0), 10.0);
+        assert_close(device(-61, Some(0)).estimated_distance(4.0), 3.16);
+        assert_close(device(-51, Some(-10)).estimated_distance(2.0), 1.0);
+        // Closer than 1 m.
+        assert_close(device(-35, Some(-14)).estimated_distance(2.0), 0.1);
+    }
+
+    #[test]
+    fn prefers_the_beacon_calibration() {
+        let mut device = device(-79, Some(0));
+        device.beacon = Some(Beacon::IBeacon(IBeacon {
+            uuid: uuid::Uuid::nil(),
+            major: 1,
+            minor: 2,
+            measured_power: -59,
+        }));
+        assert_close(device.estimated_distance(2.0), 10.0);
+    }
+
+    #[test]
+    fn has_no_distance_without_a_reading_or_tx_power() {
+        assert_eq!(device(i16::MIN, Some(0)).estimated_distance(2.0), None);
+        assert_eq!(device(-60, None).estimated_distance(2.0), None);
+    }
+}
@@ -0,0 +1,156 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+use btleplug::api::PeripheralProperties;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use uuid::Uuid;
+use crate::unlocker::bluetooth::beacon::{EddystoneUid, IBeacon, EDDYSTONE_SERVICE_UUID};
+
+/// A rule that recognises the trusted device by the content of its advertisements,
+/// for devices whose address changes (random private addresses, beacon apps).
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MatchRule {
+    /// The device advertises the given service UUID.
+    ServiceUuid { uuid: Uuid },
+    /// The device advertises manufacturer data for the company ID, starting with the
+    /// given hex-encoded bytes. The prefix is required: the company ID alone matches
+    /// every device of that manufacturer.
+    Manufacturer { id: u16, data_prefix: String },
+    /// The device advertises as an iBeacon with the given proximity UUID and,
+    /// if set, major and minor numbers.
+    #[serde(rename = "ibeacon")]
+    IBeacon {
+        uuid: Uuid,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        major: Option<u16>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        minor: Option<u16>,
+    },
+    /// The device advertises an Eddystone-UID frame with the given hex-encoded
+    /// namespace and, if set, instance.
+    Eddystone {
+        namespace: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        instance: Option<String>,
+    },
+}
+
+impl MatchRule {
+    /// The service UUID every device matching this rule advertises, if there is one.
+    pub fn service_uuid(&self) -> Option<Uuid> {
+        match self {
+            MatchRule::ServiceUuid { uuid } => Some(*uuid),
+            MatchRule::Eddystone { .. } => Some(EDDYSTONE_SERVICE_UUID),
+            MatchRule::Manufacturer { .. } | MatchRule::IBeacon { .. } => None,
+        }
+    }
+
+    pub fn matches(&self, properties: &PeripheralProperties) -> bool {
+        match self {
+            MatchRule::ServiceUuid { uuid } => {
+                properties.services.contains(uuid) || properties.service_data.contains_key(uuid)
+            }
+            MatchRule::Manufacturer { id, data_prefix } => {
+                match (properties.manufacturer_data.get(id), parse_hex(data_prefix)) {
+                    (Some(data), Some(prefix)) if !prefix.is_empty() => data.starts_with(&prefix),
+                    _ => false,
+                }
+            }
+            MatchRule::IBeacon { uuid, major, minor } => {
+                IBeacon::parse(&properties.manufacturer_data).is_some_and(|beacon| {
+                    beacon.uuid == *uuid
+                        && major.is_none_or(|m| m == beacon.major)
+                        && minor.is_none_or(|m| m == beacon.minor)
+                })
+            }
+            MatchRule::Eddystone { namespace, instance } => {
+                EddystoneUid::parse(&properties.service_data).is_some_and(|beacon| {
+                    parse_hex(namespace).is_some_and(|n| n == beacon.namespace)
+                        && instance
+                            .as_ref()
+                            .is_none_or(|i| parse_hex(i).is_some_and(|i| i == beacon.instance))
+                })
+            }
+        }
+    }
+}
+
+impl fmt::Display for MatchRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MatchRule::ServiceUuid { uuid } => write!(f, "service {}", uuid),
+            MatchRule::Manufacturer { id, data_prefix } => {
+                write!(f, "manufacturer 0x{:04X} data {}*", id, data_prefix)
+            }
+            MatchRule::IBeacon { uuid, major, minor } => {
+                let any = || "*".to_string();
+                write!(
+                    f,
+                    "iBeacon {} major {} minor {}",
+                    uuid,
+                    major.map(|m| m.to_string()).unwrap_or_else(any),
+                    minor.map(|m| m.to_string()).unwrap_or_else(any)
+                )
+            }
+            MatchRule::Eddystone { namespace, instance } => {
+                write!(f, "Eddystone {} instance {}", namespace, instance.as_deref().unwrap_or("*"))
+            }
+        }
+    }
+}
+
+/// Decodes a hex string such as `"4c000215"` (separators `:`, `-` and spaces are ignored).
+pub fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u8> = hex
+        .bytes()
+        .filter(|b| !matches!(b, b':' | b'-' | b' '))
+        .collect();
+    if !digits.len().is_multiple_of(2) || !digits.iter().all(u8::is_ascii_hexdigit) {
+        return None;
+    }
+    digits
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn parses_hex_with_separators() {
+        assert_eq!(parse_hex("4c000215"), Some(vec![0x4c, 0x00, 0x02, 0x15]));
+        assert_eq!(parse_hex("4C:00-02 15"), Some(vec![0x4c, 0x00, 0x02, 0x15]));
+        assert_eq!(parse_hex(""), Some(vec![]));
+    }
+
+    #[test]
+    fn rejects_invalid_hex() {
+        assert_eq!(parse_hex("4c0"), None);
+        assert_eq!(parse_hex("zz"), None);
+        assert_eq!(parse_hex("+1"), None);
+    }
+
+    #[test]
+    fn manufacturer_rule_needs_the_prefix() {
+        let properties = PeripheralProperties {
+            manufacturer_data: HashMap::from([(0x0075, vec![0x42, 0x04, 0x01])]),
+            ..PeripheralProperties::default()
+        };
+        let rule = |data_prefix: &str| MatchRule::Manufacturer { id: 0x0075, data_prefix: data_prefix.to_string() };
+        assert!(rule("4204").matches(&properties));
+        assert!(!rule("4205").matches(&properties));
+        assert!(!rule("").matches(&properties));
+        assert!(!rule("42040102").matches(&properties));
+    }
+}
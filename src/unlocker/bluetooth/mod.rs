@@ -7,6 +7,9 @@
  * See the LICENSE file for details.
  */
 
+pub mod beacon;
 pub mod device;
+pub mod match_rule;
+#[allow(clippy::module_inception)]
 pub mod bluetooth;
-pub use bluetooth::start_scan;
\ No newline at end of file
+pub use bluetooth::ScanOptions;
\ No newline at end of file
@@ -0,0 +1,139 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+use serde::Serialize;
+use std::error::Error as StdError;
+use std::fmt;
+use std::path::Path;
+
+/// File format of the config, chosen by the file extension.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+}
+
+impl ConfigFormat {
+    pub const ALL: [ConfigFormat; 2] = [ConfigFormat::Toml, ConfigFormat::Json];
+
+    pub fn from_path(path: &Path) -> ConfigFormat {
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => ConfigFormat::Toml,
+            _ => ConfigFormat::Json,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ConfigFormat> {
+        ConfigFormat::ALL.into_iter().find(|f| f.extension() == name)
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ConfigFormat::Json => "json",
+            ConfigFormat::Toml => "toml",
+        }
+    }
+
+    /// Parses the file contents into a JSON value, the common representation that
+    /// migrations operate on.
+    pub fn parse(&self, contents: &str) -> Result<serde_json::Value, Box<dyn StdError>> {
+        match self {
+            ConfigFormat::Json => Ok(serde_json::from_str(contents)?),
+            ConfigFormat::Toml => {
+                let value: toml::Value = toml::from_str(contents)?;
+                Ok(serde_json::to_value(value)?)
+            }
+        }
+    }
+
+    pub fn serialize<T: Serialize>(&self, value: &T) -> Result<String, Box<dyn StdError>> {
+        match self {
+            ConfigFormat::Json => Ok(serde_json::to_string_pretty(value)?),
+            ConfigFormat::Toml => Ok(toml::to_string_pretty(value)?),
+        }
+    }
+}
+
+impl fmt::Display for ConfigFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigFormat::Json => write!(f, "JSON"),
+            ConfigFormat::Toml => write!(f, "TOML"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unlocker::bluetooth::match_rule::MatchRule;
+    use crate::unlocker::config::{migration, Config, MetricsSettings, MqttSettings, NetworkPolicy};
+    use serde_json::json;
+
+    /// A config using most settings, including optional and nested ones.
+    fn full_config() -> Config {
+        let mut config: Config = serde_json::from_value(json!({
+            "version": migration::CURRENT_VERSION,
+            "device": {
+                "id": "AA:BB:CC:DD:EE:FF",
+                "name": "Phone",
+                "match_rules": [
+                    { "type": "manufacturer", "id": 117, "data_prefix": "4204" },
+                    { "type": "ibeacon", "uuid": "01020304-0506-0708-090a-0b0c0d0e0f10", "major": 1 },
+                    { "type": "eddystone", "namespace": "11111111111111111111" }
+                ]
+            },
+            "distance": -65,
+            "distance_metres": 1.5,
+            "schedule": [{ "name": "Night", "days": ["Fri", "Sat"], "start": "22:00", "end": "06:00" }],
+            "require_auth_within_hours": 8,
+        }))
+        .unwrap();
+        config.network = Some(NetworkPolicy { allowed_ssids: vec!["Home".to_string()], ..NetworkPolicy::default() });
+        config.metrics = Some(MetricsSettings::default());
+        config.mqtt = Some(MqttSettings { username: Some("bunlock".to_string()), ..MqttSettings::default() });
+        config
+    }
+
+    fn round_trip(format: ConfigFormat, config: &Config) -> Config {
+        let contents = format.serialize(config).unwrap();
+        serde_json::from_value(format.parse(&contents).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn round_trips_through_both_formats() {
+        let config = full_config();
+        let expected = serde_json::to_value(&config).unwrap();
+        for format in ConfigFormat::ALL {
+            let parsed = round_trip(format, &config);
+            assert_eq!(serde_json::to_value(&parsed).unwrap(), expected, "{}", format);
+        }
+        let converted = round_trip(ConfigFormat::Json, &round_trip(ConfigFormat::Toml, &config));
+        assert_eq!(serde_json::to_value(&converted).unwrap(), expected);
+        assert!(matches!(converted.device.match_rules[0], MatchRule::Manufacturer { id: 117, .. }));
+    }
+
+    #[test]
+    fn round_trips_the_defaults() {
+        let config = Config::default();
+        let expected = serde_json::to_value(&config).unwrap();
+        for format in ConfigFormat::ALL {
+            assert_eq!(serde_json::to_value(round_trip(format, &config)).unwrap(), expected, "{}", format);
+        }
+    }
+
+    #[test]
+    fn picks_the_format_by_extension() {
+        assert_eq!(ConfigFormat::from_path(Path::new("config.toml")), ConfigFormat::Toml);
+        assert_eq!(ConfigFormat::from_path(Path::new("config.json")), ConfigFormat::Json);
+        assert_eq!(ConfigFormat::from_path(Path::new("config")), ConfigFormat::Json);
+        assert_eq!(ConfigFormat::from_name("toml"), Some(ConfigFormat::Toml));
+        assert_eq!(ConfigFormat::from_name("yaml"), None);
+    }
+}
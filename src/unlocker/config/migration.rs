@@ -0,0 +1,111 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+use serde_json::{Map, Value};
+use std::error::Error as StdError;
+use std::path::{Path, PathBuf};
+
+/// Version written to new config files. Bump it together with a new entry in `MIGRATIONS`.
+pub const CURRENT_VERSION: u32 = 1;
+
+type Migration = fn(&mut Map<String, Value>);
+
+/// `MIGRATIONS[n]` upgrades a config from version `n` to `n + 1`.
+const MIGRATIONS: [Migration; CURRENT_VERSION as usize] = [migrate_v0_to_v1];
+
+/// Upgrades a parsed config to `CURRENT_VERSION` in place and returns the version it had.
+/// Files written before versioning was introduced have no `version` key and count as 0.
+pub fn migrate(value: &mut Value) -> Result<u32, Box<dyn StdError>> {
+    let map = value.as_object_mut().ok_or("Config must be an object")?;
+    let version = match map.get("version") {
+        None => 0,
+        Some(v) => v
+            .as_u64()
+            .and_then(|v| u32::try_from(v).ok())
+            .ok_or("Config version must be a non-negative integer")?,
+    };
+    if version > CURRENT_VERSION {
+        return Err(format!(
+            "Config version {} is newer than the supported version {}",
+            version, CURRENT_VERSION
+        )
+        .into());
+    }
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(map);
+    }
+    map.insert("version".to_string(), Value::from(CURRENT_VERSION));
+    Ok(version)
+}
+
+/// Where the original file is kept before it is overwritten by a migrated one.
+pub fn backup_path(path: &Path, version: u32) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(format!(".v{}.bak", version));
+    path.with_file_name(file_name)
+}
+
+/// Version 0 files could lack `delay_seconds` and `distance`, which used to be required.
+fn migrate_v0_to_v1(map: &mut Map<String, Value>) {
+    map.entry("distance").or_insert(Value::from(0));
+    map.entry("delay_seconds").or_insert(Value::from(0));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn migrates_unversioned_files() {
+        let mut value = json!({ "device": {}, "scan": { "filter_by_service": true } });
+        assert_eq!(migrate(&mut value).unwrap(), 0);
+        assert_eq!(
+            value,
+            json!({
+                "device": {},
+                "distance": 0,
+                "delay_seconds": 0,
+                "scan": { "filter_by_service": true },
+                "version": CURRENT_VERSION,
+            })
+        );
+    }
+
+    #[test]
+    fn keeps_values_of_older_files() {
+        let mut value = json!({ "version": 0, "distance": -70, "delay_seconds": 3 });
+        assert_eq!(migrate(&mut value).unwrap(), 0);
+        assert_eq!(value, json!({ "version": CURRENT_VERSION, "distance": -70, "delay_seconds": 3 }));
+    }
+
+    #[test]
+    fn leaves_current_files_alone() {
+        let original = json!({ "version": CURRENT_VERSION, "scan": { "filter_by_service": false } });
+        let mut value = original.clone();
+        assert_eq!(migrate(&mut value).unwrap(), CURRENT_VERSION);
+        assert_eq!(value, original);
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        assert!(migrate(&mut json!({ "version": CURRENT_VERSION + 1 })).is_err());
+        assert!(migrate(&mut json!({ "version": -1 })).is_err());
+        assert!(migrate(&mut json!({ "version": "1" })).is_err());
+        assert!(migrate(&mut json!([])).is_err());
+    }
+
+    #[test]
+    fn backup_path_names_the_version() {
+        assert_eq!(
+            backup_path(Path::new("/home/user/.config/bunlock/config.json"), 1),
+            PathBuf::from("/home/user/.config/bunlock/config.json.v1.bak")
+        );
+    }
+}
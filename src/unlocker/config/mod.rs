@@ -0,0 +1,659 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+use dialoguer::{theme::ColorfulTheme, Confirm, Input, Select};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use serde_json;
+use std::error::Error as StdError;
+use std::fmt;
+use std::fs::{File, OpenOptions, Permissions, create_dir_all, set_permissions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+pub use crate::unlocker::approval::ApprovalSettings;
+pub use crate::unlocker::bluetooth;
+pub use crate::unlocker::hooks::HookSettings;
+pub use crate::unlocker::metrics::MetricsSettings;
+pub use crate::unlocker::mqtt::MqttSettings;
+pub use crate::unlocker::notifications::NotificationSettings;
+pub use crate::unlocker::paths;
+pub use crate::unlocker::policy::network::NetworkPolicy;
+pub use crate::unlocker::policy::rate_limit::RateLimitSettings;
+pub use crate::unlocker::policy::schedule::ScheduleRule;
+pub use crate::unlocker::service;
+pub use format::ConfigFormat;
+pub use validation::ValidationError;
+pub mod format;
+pub mod migration;
+pub mod validation;
+pub mod watcher;
+
+const CONFIG_NAME: &str = "config";
+/// Environment variable overriding the config file path.
+pub const CONFIG_ENV: &str = "BUNLOCK_CONFIG";
+
+/// Path of the config file, in order of precedence: the `--config` flag, the
+/// `BUNLOCK_CONFIG` variable, then `config.toml` or `config.json` in the XDG config dir.
+pub fn config_path(override_path: Option<&Path>) -> PathBuf {
+    explicit_config_path(override_path).unwrap_or_else(|| config_path_in(&paths::config_dir()))
+}
+
+/// The config path given with the `--config` flag or the `BUNLOCK_CONFIG` variable, if any.
+fn explicit_config_path(override_path: Option<&Path>) -> Option<PathBuf> {
+    let path = override_path
+        .map(Path::to_path_buf)
+        .or_else(|| std::env::var_os(CONFIG_ENV).filter(|p| !p.is_empty()).map(PathBuf::from))?;
+    Some(std::path::absolute(&path).unwrap_or(path))
+}
+
+/// The config file in `dir`: TOML if it exists, JSON otherwise.
+pub fn config_path_in(dir: &Path) -> PathBuf {
+    let toml_path = dir.join(CONFIG_NAME).with_extension(ConfigFormat::Toml.extension());
+    if toml_path.exists() {
+        toml_path
+    } else {
+        dir.join(CONFIG_NAME).with_extension(ConfigFormat::Json.extension())
+    }
+}
+
+/// Numbers the temporary files of concurrent saves within the process.
+static TEMP_FILE_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// Creates a 0600 temporary file for saving. The name is unique among running
+/// processes, so a file that already has it was left behind by a crashed one and
+/// is replaced.
+fn create_temp_file(path: &Path) -> io::Result<File> {
+    let open = || OpenOptions::new().write(true).create_new(true).mode(0o600).open(path);
+    match open() {
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            std::fs::remove_file(path)?;
+            open()
+        }
+        result => result,
+    }
+}
+
+/// What the presence of the trusted device is used for.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum UnlockMode {
+    /// The daemon unlocks the session when the device comes near, and the PAM module
+    /// accepts the device instead of a password.
+    #[default]
+    Proximity,
+    /// The device never unlocks on its own; the PAM module denies authentication
+    /// while it is absent, so it is needed in addition to the password.
+    SecondFactor,
+}
+
+impl fmt::Display for UnlockMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnlockMode::Proximity => write!(f, "proximity unlock"),
+            UnlockMode::SecondFactor => write!(f, "second factor"),
+        }
+    }
+}
+
+/// Which signal the daemon uses to decide that the trusted device is nearby.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PresenceMode {
+    /// RSSI of the device's advertisements.
+    #[default]
+    Advertisement,
+    /// A GATT connection to the bonded device and its connected RSSI.
+    Connection,
+}
+
+impl fmt::Display for PresenceMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PresenceMode::Advertisement => write!(f, "advertisement RSSI"),
+            PresenceMode::Connection => write!(f, "GATT connection"),
+        }
+    }
+}
+
+/// Controls how much the daemon scans, to save power while the session is unlocked.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct ScanSettings {
+    /// Filter discovery to the services named by the match rules. The device is then
+    /// no longer found by its address.
+    pub filter_by_service: bool,
+    /// How long a scan lasts while the session is unlocked.
+    pub unlocked_scan_window_seconds: u64,
+    /// How often a scan is started while the session is unlocked.
+    pub unlocked_scan_interval_seconds: u64,
+}
+
+impl Default for ScanSettings {
+    fn default() -> Self {
+        ScanSettings {
+            filter_by_service: false,
+            unlocked_scan_window_seconds: 5,
+            unlocked_scan_interval_seconds: 60,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Config {
+    /// File the config was loaded from and is saved to.
+    #[serde(skip)]
+    pub path: PathBuf,
+    #[serde(default)]
+    pub version: u32,
+    #[serde(
+        serialize_with = "bluetooth::device::Device::serialize",
+        deserialize_with = "bluetooth::device::Device::deserialize"
+    )]
+    pub device: bluetooth::device::Device,
+    /// RSSI threshold (dBm) the device signal must reach for unlocking.
+    #[serde(default)]
+    pub distance: i16,
+    #[serde(default)]
+    pub delay_seconds: u32,
+    #[serde(default)]
+    pub unlock_mode: UnlockMode,
+    #[serde(default)]
+    pub presence_mode: PresenceMode,
+    /// In connection mode, count a connected device BlueZ has no RSSI reading for as
+    /// in range. A connection can reach across a building, so this is off by default.
+    #[serde(default)]
+    pub connection_counts_as_presence: bool,
+    /// Estimated distance (m) for unlocking. Takes precedence over the RSSI
+    /// threshold whenever the device advertises its TX power.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub distance_metres: Option<f64>,
+    #[serde(default = "default_path_loss_exponent")]
+    pub path_loss_exponent: f64,
+    #[serde(default)]
+    pub scan: ScanSettings,
+    /// Time windows in which proximity unlock is allowed. Empty means always.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub schedule: Vec<ScheduleRule>,
+    /// Networks on which proximity unlock is allowed. Unset means any network.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub network: Option<NetworkPolicy>,
+    /// Only unlock if the password was entered within this many hours. Unset means
+    /// no such requirement.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub require_auth_within_hours: Option<u32>,
+    #[serde(default)]
+    pub rate_limit: RateLimitSettings,
+    /// Approval of admin actions over D-Bus. Disabled when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub approval: Option<ApprovalSettings>,
+    #[serde(default)]
+    pub notifications: NotificationSettings,
+    #[serde(default)]
+    pub hooks: HookSettings,
+    /// Prometheus metrics endpoint. Disabled when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metrics: Option<MetricsSettings>,
+    /// Publishing of presence and events over MQTT. Disabled when unset.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mqtt: Option<MqttSettings>,
+}
+
+fn default_path_loss_exponent() -> f64 {
+    2.0
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            path: PathBuf::new(),
+            version: migration::CURRENT_VERSION,
+            device: bluetooth::device::Device::default(),
+            distance: 0,
+            delay_seconds: 0,
+            unlock_mode: UnlockMode::default(),
+            presence_mode: PresenceMode::default(),
+            connection_counts_as_presence: false,
+            distance_metres: None,
+            path_loss_exponent: default_path_loss_exponent(),
+            scan: ScanSettings::default(),
+            schedule: vec![],
+            network: None,
+            require_auth_within_hours: None,
+            rate_limit: RateLimitSettings::default(),
+            approval: None,
+            notifications: NotificationSettings::default(),
+            hooks: HookSettings::default(),
+            metrics: None,
+            mqtt: None,
+        }
+    }
+}
+
+impl Config {
+    pub async fn new(path: &Path) -> Result<Self, Box<dyn StdError>> {
+        if path.exists() {
+            Config::load_from_file(path).await
+        } else {
+            Ok(Config {
+                path: path.to_path_buf(),
+                ..Config::default()
+            })
+        }
+    }
+
+    /// Like `new`, but doesn't reject an invalid file, so that it can be fixed or inspected.
+    pub async fn read(path: &Path) -> Result<Self, Box<dyn StdError>> {
+        if path.exists() {
+            Config::read_from_file(path).await
+        } else {
+            Ok(Config {
+                path: path.to_path_buf(),
+                ..Config::default()
+            })
+        }
+    }
+
+    pub fn validate(&self) -> Vec<ValidationError> {
+        validation::validate(self)
+    }
+
+    pub fn scan_options(&self) -> bluetooth::ScanOptions {
+        bluetooth::ScanOptions {
+            filter: if self.scan.filter_by_service {
+                self.device.scan_filter()
+            } else {
+                btleplug::api::ScanFilter::default()
+            },
+        }
+    }
+
+    /// Checks the device's last reading against the configured threshold, preferring
+    /// the estimated distance and falling back to raw RSSI when it can't be estimated.
+    pub fn is_in_range(&self, device: &bluetooth::device::Device) -> bool {
+        if device.connected && device.rssi == i16::MIN {
+            return self.connection_counts_as_presence;
+        }
+        if let Some(max_distance) = self.distance_metres {
+            if let Some(distance) = device.estimated_distance(self.path_loss_exponent) {
+                return distance <= max_distance;
+            }
+            debug!("TX power of {} is unknown, falling back to the RSSI threshold", device);
+        }
+        self.distance <= device.rssi
+    }
+
+    pub async fn load_from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn StdError>> {
+        Config::read_from_file(path).await?.validated()
+    }
+
+    /// Like `load_from_file`, but never writes: an older file is migrated in memory
+    /// only. For reading a file that root must not modify, and for reloading.
+    pub fn load_read_only<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn StdError>> {
+        let (config, _) = Config::parse_file(path.as_ref())?;
+        config.validated()
+    }
+
+    fn validated(self) -> Result<Self, Box<dyn StdError>> {
+        let errors = self.validate();
+        if !errors.is_empty() {
+            let problems: Vec<String> = errors.iter().map(|e| format!("  {}", e)).collect();
+            return Err(format!("Invalid config {}:\n{}", self.path.display(), problems.join("\n")).into());
+        }
+        Ok(self)
+    }
+
+    /// Parses the file, migrated to the current version, along with the version the
+    /// file has.
+    fn parse_file(path: &Path) -> Result<(Self, u32), Box<dyn StdError>> {
+        let mut file = File::open(path)?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let mut value = ConfigFormat::from_path(path).parse(&contents)?;
+        let version = migration::migrate(&mut value)?;
+        let mut config: Config =
+            serde_json::from_value(value).map_err(|e| Box::new(e) as Box<dyn StdError>)?;
+        config.path = path.to_path_buf();
+        Ok((config, version))
+    }
+
+    async fn read_from_file<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn StdError>> {
+        let path = path.as_ref();
+        let (config, version) = Config::parse_file(path)?;
+        if version < migration::CURRENT_VERSION {
+            let backup_path = migration::backup_path(path, version);
+            std::fs::copy(path, &backup_path)?;
+            set_permissions(&backup_path, Permissions::from_mode(0o600))?;
+            config.save_to_file(path).await?;
+            info!(
+                "Config migrated from version {} to {}, original saved to {}",
+                version,
+                migration::CURRENT_VERSION,
+                backup_path.display()
+            );
+        }
+        Ok(config)
+    }
+
+    /// Writes the config without ever leaving a partial or world-readable file behind:
+    /// the contents go to a 0600 temporary file in the same directory, which is synced
+    /// and renamed over the original. The previous version is kept as `<name>.bak`. A
+    /// symlink is kept, and its target written instead.
+    pub async fn save_to_file<P: AsRef<Path>>(&self, path: P) -> Result<(), Box<dyn StdError>> {
+        let path = match std::fs::canonicalize(path.as_ref()) {
+            Ok(target) => target,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => path.as_ref().to_path_buf(),
+            Err(e) => return Err(e.into()),
+        };
+        let path = path.as_path();
+        let parent = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        create_dir_all(parent)?;
+
+        let contents = ConfigFormat::from_path(path).serialize(self)?;
+        let file_name = path.file_name().ok_or("Config path has no file name")?;
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(file_name);
+        let count = TEMP_FILE_COUNT.fetch_add(1, Ordering::Relaxed);
+        temp_name.push(format!(".{}.{}.tmp", std::process::id(), count));
+        let temp_path = parent.join(temp_name);
+
+        let result = (|| -> io::Result<()> {
+            let mut file = create_temp_file(&temp_path)?;
+            file.write_all(contents.as_bytes())?;
+            file.sync_all()?;
+            if path.exists() {
+                let mut backup_name = file_name.to_os_string();
+                backup_name.push(".bak");
+                let backup_path = parent.join(backup_name);
+                std::fs::copy(path, &backup_path)?;
+                set_permissions(&backup_path, Permissions::from_mode(0o600))?;
+            }
+            std::fs::rename(&temp_path, path)?;
+            File::open(parent)?.sync_all()
+        })();
+        if result.is_err() {
+            let _ = std::fs::remove_file(&temp_path);
+        }
+        Ok(result?)
+    }
+
+    /// Rewrites the config file in the given format. The file in the other format is
+    /// kept as a `.converted.bak` so that only one of them is picked up, without
+    /// replacing the `.bak` that saving keeps.
+    pub async fn convert(&self, format: ConfigFormat) -> Result<PathBuf, Box<dyn StdError>> {
+        let old_path = self.path.clone();
+        let new_path = old_path.with_extension(format.extension());
+        self.save_to_file(&new_path).await?;
+        if old_path != new_path && old_path.exists() {
+            let mut backup_name = old_path.file_name().unwrap_or_default().to_os_string();
+            backup_name.push(".converted.bak");
+            std::fs::rename(&old_path, old_path.with_file_name(backup_name))?;
+        }
+        Ok(new_path)
+    }
+
+    pub async fn configurate(&mut self) -> Result<(), Box<dyn StdError>> {
+        let theme = ColorfulTheme::default();
+        let mut selected_index: usize = 0;
+        loop {
+            let menu_items = [
+                format!("Select Bluetooth device (current: {})", self.device.name),
+                format!("RSSI threshold for unlocking (current: {} dBm)", self.distance),
+                format!(
+                    "Distance for unlocking (current: {})",
+                    self.distance_metres
+                        .map(|d| format!("{:.1} m", d))
+                        .unwrap_or("not set".to_string())
+                ),
+                format!("Path-loss exponent (current: {})", self.path_loss_exponent),
+                format!("Presence detection (current: {})", self.presence_mode),
+                format!("Unlock mode (current: {})", self.unlock_mode),
+                "Save and Exit".to_string(),
+            ];
+
+            selected_index = Select::with_theme(&theme)
+                .with_prompt("Main menu")
+                .default(selected_index)
+                .items(&menu_items[..])
+                .interact()?;
+
+            match selected_index {
+                0 => {
+                    let devices: Vec<bluetooth::device::Device> =
+                        bluetooth::device::get_all().await?;
+                    let mut device_names: Vec<String> = vec![];
+                    let mut default = 0;
+                    for (index, device) in devices.iter().enumerate() {
+                        device_names.push(device.to_string());
+                        if device.id == self.device.id {
+                            default = index;
+                        }
+                    }
+                    let selected_device_index = Select::with_theme(&theme)
+                        .with_prompt("Select Bluetooth device for unlocking")
+                        .default(default)
+                        .items(&device_names[..])
+                        .interact_opt()?;
+                    if let Some(index) = selected_device_index {
+                        let mut device = devices[index].clone();
+                        if let Some(beacon) = &device.beacon {
+                            device.match_rules = vec![beacon.match_rule()];
+                        } else if device.id == self.device.id {
+                            device.match_rules = self.device.match_rules.clone();
+                        }
+                        self.device = device;
+                    }
+                }
+                1 => {
+                    let rssi = self.read_device_rssi().await;
+                    let distance_input: String = Input::with_theme(&theme)
+                        .with_prompt(format!("Current RSSI: {}\nEnter RSSI threshold for unlocking (in dBm)", rssi))
+                        .default(self.distance.to_string())
+                        .validate_with(|input: &String| -> Result<(), String> {
+                            let rssi = input.trim().parse().map_err(|_| "Enter a whole number".to_string())?;
+                            validation::check_rssi_threshold(rssi)
+                        })
+                        .interact_text()?;
+                    self.distance = distance_input.trim().parse()?;
+                }
+                2 => {
+                    let rssi = self.read_device_rssi().await;
+                    let estimate = match self.device.estimated_distance(self.path_loss_exponent) {
+                        Some(distance) => format!("{:.1} m", distance),
+                        None => "unknown (no TX power advertised)".to_string(),
+                    };
+                    let distance_input: String = Input::with_theme(&theme)
+                        .with_prompt(format!(
+                            "Current RSSI: {}, estimated distance: {}\nEnter distance for unlocking (in metres, empty to use the RSSI threshold)",
+                            rssi, estimate
+                        ))
+                        .default(self.distance_metres.map(|d| d.to_string()).unwrap_or_default())
+                        .allow_empty(true)
+                        .validate_with(|input: &String| -> Result<(), String> {
+                            if input.trim().is_empty() {
+                                return Ok(());
+                            }
+                            let distance = input.trim().parse().map_err(|_| "Enter a number".to_string())?;
+                            validation::check_distance_metres(distance)
+                        })
+                        .interact_text()?;
+                    self.distance_metres = match distance_input.trim() {
+                        "" => None,
+                        distance => Some(distance.parse()?),
+                    };
+                }
+                3 => {
+                    let exponent_input: String = Input::with_theme(&theme)
+                        .with_prompt("Enter path-loss exponent (2.0 in open space, up to 4.0 indoors)")
+                        .default(self.path_loss_exponent.to_string())
+                        .validate_with(|input: &String| -> Result<(), String> {
+                            let exponent = input.trim().parse().map_err(|_| "Enter a number".to_string())?;
+                            validation::check_path_loss_exponent(exponent)
+                        })
+                        .interact_text()?;
+                    self.path_loss_exponent = exponent_input.trim().parse()?;
+                }
+                4 => {
+                    let modes = [PresenceMode::Advertisement, PresenceMode::Connection];
+                    let mode_names: Vec<String> = modes.iter().map(|m| m.to_string()).collect();
+                    let default = modes.iter().position(|m| *m == self.presence_mode).unwrap_or(0);
+                    let selected_mode_index = Select::with_theme(&theme)
+                        .with_prompt("Select how device presence is detected")
+                        .default(default)
+                        .items(&mode_names[..])
+                        .interact_opt()?;
+                    if let Some(index) = selected_mode_index {
+                        self.presence_mode = modes[index];
+                    }
+                    if self.presence_mode == PresenceMode::Connection {
+                        self.connection_counts_as_presence = Confirm::with_theme(&theme)
+                            .with_prompt("Count the connected device as in range when there is no RSSI reading?")
+                            .default(self.connection_counts_as_presence)
+                            .interact()?;
+                    }
+                }
+                5 => {
+                    let modes = [UnlockMode::Proximity, UnlockMode::SecondFactor];
+                    let mode_names: Vec<String> = modes.iter().map(|m| m.to_string()).collect();
+                    let default = modes.iter().position(|m| *m == self.unlock_mode).unwrap_or(0);
+                    let selected_mode_index = Select::with_theme(&theme)
+                        .with_prompt("Select what the device is used for")
+                        .default(default)
+                        .items(&mode_names[..])
+                        .interact_opt()?;
+                    if let Some(index) = selected_mode_index {
+                        self.unlock_mode = modes[index];
+                    }
+                }
+                6 => {
+                    let errors = self.validate();
+                    if !errors.is_empty() {
+                        eprintln!("The configuration can't be saved:");
+                        for error in errors {
+                            eprintln!("  {}", error);
+                        }
+                        continue;
+                    }
+                    self.save_to_file(&self.path).await?;
+                    if service::is_running()? {
+                        service::reload()?;
+                    }
+                    break;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    async fn read_device_rssi(&mut self) -> String {
+        let device = &mut self.device;
+        device.update_rssi().await;
+        if device.rssi.eq(&i16::MIN) {
+            "Device not found".to_string()
+        } else {
+            device.rssi.to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn convert_keeps_the_save_backup() {
+        let dir = std::env::temp_dir().join(format!("bunlock-convert-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        let mut config = Config { path: dir.join("config.json"), ..Config::default() };
+        config.device.id = "AA:BB:CC:DD:EE:FF".to_string();
+        config.distance = -60;
+        config.save_to_file(&config.path).await.unwrap();
+        config.distance = -70;
+        config.save_to_file(&config.path).await.unwrap();
+
+        let new_path = config.convert(ConfigFormat::Toml).await.unwrap();
+        assert_eq!(new_path, dir.join("config.toml"));
+        assert!(!dir.join("config.json").exists());
+        let saved = Config::load_read_only(dir.join("config.json.bak")).unwrap();
+        let converted = Config::load_read_only(dir.join("config.json.converted.bak")).unwrap();
+        assert_eq!((saved.distance, converted.distance), (-60, -70));
+        assert_eq!(Config::load_read_only(&new_path).unwrap().distance, -70);
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[tokio::test]
+    async fn saves_through_symlinks() {
+        let dir = std::env::temp_dir().join(format!("bunlock-symlink-{}", std::process::id()));
+        std::fs::remove_dir_all(&dir).ok();
+        std::fs::create_dir_all(dir.join("dotfiles")).unwrap();
+        let target = dir.join("dotfiles/config.json");
+        let link = dir.join("config.json");
+        let mut config = Config::default();
+        config.device.id = "AA:BB:CC:DD:EE:FF".to_string();
+        config.distance = -60;
+        config.save_to_file(&target).await.unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        config.distance = -70;
+        config.save_to_file(&link).await.unwrap();
+        assert!(link.symlink_metadata().unwrap().file_type().is_symlink());
+        assert_eq!(Config::load_read_only(&target).unwrap().distance, -70);
+        assert!(dir.join("dotfiles/config.json.bak").exists());
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn replaces_stale_temp_files() {
+        let path = std::env::temp_dir().join(format!(".bunlock-stale-{}.tmp", std::process::id()));
+        std::fs::write(&path, "partial").unwrap();
+        let mut file = create_temp_file(&path).unwrap();
+        file.write_all(b"new").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        assert_eq!(path.metadata().unwrap().permissions().mode() & 0o777, 0o600);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn distance_falls_back_to_the_rssi_threshold() {
+        let config = Config { distance: -70, distance_metres: Some(2.0), ..Config::default() };
+        let mut device = bluetooth::device::Device::default();
+        // Without TX power only the RSSI threshold counts.
+        device.rssi = -65;
+        assert!(config.is_in_range(&device));
+        device.rssi = -75;
+        assert!(!config.is_in_range(&device));
+        // With it the distance decides: -65 dBm is about 16 m from a 0 dBm transmitter.
+        device.tx_power = Some(0);
+        device.rssi = -65;
+        assert!(!config.is_in_range(&device));
+        device.rssi = -45;
+        assert!(config.is_in_range(&device));
+    }
+
+    #[test]
+    fn connection_without_rssi_counts_only_when_enabled() {
+        let mut config = Config { distance: -70, ..Config::default() };
+        let mut device = bluetooth::device::Device::default();
+        device.connected = true;
+        assert!(!config.is_in_range(&device));
+        config.connection_counts_as_presence = true;
+        assert!(config.is_in_range(&device));
+        // A reading decides whenever there is one.
+        device.rssi = -90;
+        assert!(!config.is_in_range(&device));
+        device.connected = false;
+        device.rssi = i16::MIN;
+        assert!(!config.is_in_range(&device));
+    }
+}
@@ -0,0 +1,310 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+use std::fmt;
+use crate::unlocker::bluetooth::match_rule::{parse_hex, MatchRule};
+use crate::unlocker::config::Config;
+
+/// Longest accepted `delay_seconds`.
+const MAX_DELAY_SECONDS: u32 = 3600;
+
+/// A problem with one config value, identified by its key path (e.g. `scan.unlocked_scan_window_seconds`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+pub fn validate(config: &Config) -> Vec<ValidationError> {
+    let mut errors = vec![];
+    let mut check = |path: &str, result: Result<(), String>| {
+        if let Err(message) = result {
+            errors.push(ValidationError { path: path.to_string(), message });
+        }
+    };
+
+    check("device.id", check_mac_address(&config.device.id));
+    for (index, rule) in config.device.match_rules.iter().enumerate() {
+        let path = format!("device.match_rules[{}]", index);
+        match rule {
+            MatchRule::ServiceUuid { .. } | MatchRule::IBeacon { .. } => {}
+            MatchRule::Manufacturer { data_prefix, .. } => {
+                let result = if parse_hex(data_prefix).is_some_and(|prefix| prefix.is_empty()) {
+                    Err("must not be empty, or any device of the manufacturer matches".to_string())
+                } else {
+                    check_hex(data_prefix, None)
+                };
+                check(&format!("{}.data_prefix", path), result);
+            }
+            MatchRule::Eddystone { namespace, instance } => {
+                check(&format!("{}.namespace", path), check_hex(namespace, Some(10)));
+                if let Some(instance) = instance {
+                    check(&format!("{}.instance", path), check_hex(instance, Some(6)));
+                }
+            }
+        }
+    }
+    check("distance", check_rssi_threshold(config.distance));
+    if config.delay_seconds > MAX_DELAY_SECONDS {
+        check("delay_seconds", Err(format!("must be at most {} (one hour)", MAX_DELAY_SECONDS)));
+    }
+    if let Some(distance) = config.distance_metres {
+        check("distance_metres", check_distance_metres(distance));
+    }
+    check("path_loss_exponent", check_path_loss_exponent(config.path_loss_exponent));
+    check(
+        "scan.unlocked_scan_window_seconds",
+        check_positive(config.scan.unlocked_scan_window_seconds),
+    );
+    check(
+        "scan.unlocked_scan_interval_seconds",
+        check_positive(config.scan.unlocked_scan_interval_seconds).and_then(|_| {
+            if config.scan.unlocked_scan_interval_seconds < config.scan.unlocked_scan_window_seconds {
+                Err("must not be shorter than scan.unlocked_scan_window_seconds".to_string())
+            } else {
+                Ok(())
+            }
+        }),
+    );
+    for (index, rule) in config.schedule.iter().enumerate() {
+        if rule.days.is_empty() {
+            check(&format!("schedule[{}].days", index), Err("no days listed".to_string()));
+        }
+        if rule.start == rule.end {
+            check(&format!("schedule[{}].end", index), Err("must differ from start".to_string()));
+        }
+    }
+    if let Some(network) = &config.network {
+        if network.is_empty() {
+            check("network", Err("no network is allowed, so unlock would never happen".to_string()));
+        }
+    }
+    if config.require_auth_within_hours == Some(0) {
+        check("require_auth_within_hours", Err("must be greater than 0".to_string()));
+    }
+    if let Some(approval) = &config.approval {
+        check("approval.presence_timeout_seconds", check_positive(approval.presence_timeout_seconds));
+        if approval.confirm {
+            check("approval.confirm_timeout_seconds", check_positive(approval.confirm_timeout_seconds));
+        }
+    }
+    if config.scan.filter_by_service && config.device.scan_filter().services.is_empty() {
+        check(
+            "scan.filter_by_service",
+            Err("needs match rules that all name a service (service_uuid or eddystone)".to_string()),
+        );
+    }
+    check("hooks.timeout_seconds", check_positive(config.hooks.timeout_seconds));
+    if let Some(metrics) = &config.metrics {
+        check("metrics.listen", check_listen(&metrics.listen));
+    }
+    if let Some(mqtt) = &config.mqtt {
+        if mqtt.host.is_empty() {
+            check("mqtt.host", Err("must not be empty".to_string()));
+        }
+        if mqtt.port == 0 {
+            check("mqtt.port", Err("must be greater than 0".to_string()));
+        }
+        if mqtt.password.is_some() && mqtt.username.is_none() {
+            check("mqtt.password", Err("requires mqtt.username".to_string()));
+        }
+        if mqtt.password.is_some() && !mqtt.tls && !mqtt.is_local() {
+            check(
+                "mqtt.password",
+                Err("would be sent in clear text to a remote broker, enable mqtt.tls".to_string()),
+            );
+        }
+        if mqtt.ca_file.is_some() && !mqtt.tls {
+            check("mqtt.ca_file", Err("requires mqtt.tls".to_string()));
+        }
+        if let Some(topic) = &mqtt.topic {
+            check("mqtt.topic", check_topic(topic));
+        }
+        if mqtt.discovery {
+            check("mqtt.discovery_prefix", check_topic(&mqtt.discovery_prefix));
+        }
+    }
+    if config.rate_limit.max_relocks > 0 {
+        check(
+            "rate_limit.relock_window_seconds",
+            check_positive(config.rate_limit.relock_window_seconds),
+        );
+    }
+    errors
+}
+
+pub fn check_mac_address(address: &str) -> Result<(), String> {
+    let parts: Vec<&str> = address.split(':').collect();
+    let valid = parts.len() == 6
+        && parts
+            .iter()
+            .all(|p| p.len() == 2 && p.chars().all(|c| c.is_ascii_hexdigit()));
+    if address.is_empty() {
+        Err("no device selected".to_string())
+    } else if !valid {
+        Err(format!("\"{}\" is not a MAC address (expected XX:XX:XX:XX:XX:XX)", address))
+    } else {
+        Ok(())
+    }
+}
+
+pub fn check_rssi_threshold(rssi: i16) -> Result<(), String> {
+    if (-127..=20).contains(&rssi) {
+        Ok(())
+    } else {
+        Err(format!("{} dBm is out of range (-127 to 20)", rssi))
+    }
+}
+
+pub fn check_distance_metres(distance: f64) -> Result<(), String> {
+    if distance > 0.0 && distance <= 100.0 {
+        Ok(())
+    } else {
+        Err(format!("{} m is out of range (above 0 and up to 100)", distance))
+    }
+}
+
+pub fn check_path_loss_exponent(exponent: f64) -> Result<(), String> {
+    if (1.0..=6.0).contains(&exponent) {
+        Ok(())
+    } else {
+        Err(format!("{} is out of range (1.0 to 6.0)", exponent))
+    }
+}
+
+/// `host:port` or an absolute path of a Unix socket.
+pub fn check_listen(listen: &str) -> Result<(), String> {
+    if listen.starts_with('/') || listen.parse::<std::net::SocketAddr>().is_ok() {
+        Ok(())
+    } else {
+        Err(format!("\"{}\" is neither host:port (e.g. 127.0.0.1:9877) nor an absolute socket path", listen))
+    }
+}
+
+/// An MQTT topic to publish to: not empty and without wildcards.
+pub fn check_topic(topic: &str) -> Result<(), String> {
+    if topic.is_empty() || topic.starts_with('/') || topic.ends_with('/') {
+        Err(format!("\"{}\" must not be empty or start or end with /", topic))
+    } else if topic.contains(['+', '#']) {
+        Err(format!("\"{}\" must not contain the wildcards + or #", topic))
+    } else {
+        Ok(())
+    }
+}
+
+fn check_positive(seconds: u64) -> Result<(), String> {
+    if seconds > 0 {
+        Ok(())
+    } else {
+        Err("must be greater than 0".to_string())
+    }
+}
+
+fn check_hex(hex: &str, expected_len: Option<usize>) -> Result<(), String> {
+    match (parse_hex(hex), expected_len) {
+        (None, _) => Err(format!("\"{}\" is not a hex string", hex)),
+        (Some(bytes), Some(len)) if bytes.len() != len => {
+            Err(format!("expected {} bytes, got {}", len, bytes.len()))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::unlocker::config::MqttSettings;
+
+    fn valid_config() -> Config {
+        let mut config = Config::default();
+        config.device.id = "AA:BB:CC:DD:EE:FF".to_string();
+        config.distance = -70;
+        config
+    }
+
+    fn paths(config: &Config) -> Vec<String> {
+        validate(config).into_iter().map(|error| error.path).collect()
+    }
+
+    #[test]
+    fn accepts_a_valid_config() {
+        assert_eq!(validate(&valid_config()), vec![]);
+    }
+
+    #[test]
+    fn reports_each_problem_with_its_key() {
+        let mut config = valid_config();
+        config.device.id = "AA:BB:CC".to_string();
+        config.distance = -200;
+        config.delay_seconds = 7200;
+        config.scan.unlocked_scan_window_seconds = 0;
+        config.device.match_rules = vec![
+            MatchRule::Manufacturer { id: 0x0075, data_prefix: "".to_string() },
+            MatchRule::Eddystone { namespace: "0011".to_string(), instance: Some("xy".to_string()) },
+        ];
+        assert_eq!(
+            paths(&config),
+            [
+                "device.id",
+                "device.match_rules[0].data_prefix",
+                "device.match_rules[1].namespace",
+                "device.match_rules[1].instance",
+                "distance",
+                "delay_seconds",
+                "scan.unlocked_scan_window_seconds",
+            ]
+        );
+    }
+
+    #[test]
+    fn scan_interval_must_cover_the_window() {
+        let mut config = valid_config();
+        config.scan.unlocked_scan_window_seconds = 30;
+        config.scan.unlocked_scan_interval_seconds = 10;
+        assert_eq!(paths(&config), ["scan.unlocked_scan_interval_seconds"]);
+    }
+
+    #[test]
+    fn rejects_clear_text_passwords_to_remote_brokers() {
+        let mut config = valid_config();
+        config.mqtt = Some(MqttSettings {
+            host: "broker.example.com".to_string(),
+            username: Some("bunlock".to_string()),
+            password: Some("secret".to_string()),
+            ..MqttSettings::default()
+        });
+        assert_eq!(paths(&config), ["mqtt.password"]);
+        config.mqtt.as_mut().unwrap().tls = true;
+        assert_eq!(validate(&config), vec![]);
+    }
+
+    #[test]
+    fn checks_values() {
+        assert!(check_mac_address("aa:bb:cc:dd:ee:ff").is_ok());
+        assert!(check_mac_address("").is_err());
+        assert!(check_mac_address("aa:bb:cc:dd:ee:fg").is_err());
+        assert!(check_rssi_threshold(-127).is_ok());
+        assert!(check_rssi_threshold(21).is_err());
+        assert!(check_distance_metres(0.0).is_err());
+        assert!(check_distance_metres(100.0).is_ok());
+        assert!(check_path_loss_exponent(0.5).is_err());
+        assert!(check_listen("127.0.0.1:9877").is_ok());
+        assert!(check_listen("/run/user/1000/bunlock.sock").is_ok());
+        assert!(check_listen("localhost").is_err());
+        assert!(check_topic("bunlock/host").is_ok());
+        assert!(check_topic("bunlock/+").is_err());
+        assert!(check_topic("bunlock/").is_err());
+    }
+}
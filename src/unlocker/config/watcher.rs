@@ -0,0 +1,46 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+use inotify::{Inotify, WatchMask};
+use log::{debug, error};
+use std::error::Error as StdError;
+use std::path::Path;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+/// Watches the config file and sends a message whenever it has been rewritten.
+/// The parent directory is watched rather than the file, so that editors and
+/// writers that replace the file by renaming are noticed too.
+pub fn watch(path: &Path) -> Result<UnboundedReceiver<()>, Box<dyn StdError>> {
+    let dir = path.parent().ok_or("Config path has no parent directory")?;
+    let file_name = path.file_name().ok_or("Config path has no file name")?.to_os_string();
+    std::fs::create_dir_all(dir)?;
+    let mut inotify = Inotify::init()?;
+    inotify
+        .watches()
+        .add(dir, WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE)?;
+    let (sender, receiver) = unbounded_channel();
+    debug!("Watching {} for changes", path.display());
+    std::thread::spawn(move || {
+        let mut buffer = [0; 4096];
+        loop {
+            let events = match inotify.read_events_blocking(&mut buffer) {
+                Ok(events) => events,
+                Err(e) => {
+                    error!("Failed to read config file events: {}", e);
+                    return;
+                }
+            };
+            let changed = events.into_iter().any(|event| event.name == Some(file_name.as_os_str()));
+            if changed && sender.send(()).is_err() {
+                return;
+            }
+        }
+    });
+    Ok(receiver)
+}
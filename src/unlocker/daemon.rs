@@ -0,0 +1,544 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+use log::{debug, error, info, warn};
+use std::error::Error;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::time::sleep;
+use crate::unlocker::bluetooth::device::Device;
+use crate::unlocker::policy::rate_limit::RateLimiter;
+use crate::unlocker::policy::{self, auth};
+use crate::unlocker::events::Event;
+use crate::unlocker::metrics::{self, Metrics};
+use crate::unlocker::mqtt::Publisher;
+use crate::unlocker::{approval, audit, config, hooks, lock_status, notifications};
+
+const TICK_INTERVAL: Duration = Duration::from_secs(2);
+/// Editors emit several events per save; wait for them to settle before reloading.
+const RELOAD_SETTLE_DELAY: Duration = Duration::from_millis(200);
+
+/// Decides when discovery runs: continuously while the session is locked, and for a
+/// short window every interval while it is unlocked.
+struct ScanSchedule {
+    window: Duration,
+    interval: Duration,
+    scanning: bool,
+    started_at: Option<Instant>,
+}
+
+impl ScanSchedule {
+    fn new(settings: &config::ScanSettings) -> ScanSchedule {
+        ScanSchedule {
+            window: Duration::from_secs(settings.unlocked_scan_window_seconds),
+            interval: Duration::from_secs(settings.unlocked_scan_interval_seconds),
+            scanning: true,
+            started_at: Some(Instant::now()),
+        }
+    }
+
+    fn reconfigure(&mut self, settings: &config::ScanSettings) {
+        self.window = Duration::from_secs(settings.unlocked_scan_window_seconds);
+        self.interval = Duration::from_secs(settings.unlocked_scan_interval_seconds);
+    }
+
+    /// Returns `Some(true)` when discovery should be started and `Some(false)` when
+    /// it should be stopped.
+    fn update(&mut self, locked: bool, now: Instant) -> Option<bool> {
+        let wanted = if locked {
+            true
+        } else if self.scanning {
+            self.started_at.is_some_and(|t| now.duration_since(t) < self.window)
+        } else {
+            self.started_at.is_none_or(|t| now.duration_since(t) >= self.interval)
+        };
+        if wanted && (locked || !self.scanning) {
+            self.started_at = Some(now);
+        }
+        if wanted == self.scanning {
+            return None;
+        }
+        self.scanning = wanted;
+        Some(wanted)
+    }
+
+    /// Forces discovery to be started on the next update.
+    fn restart(&mut self) {
+        self.scanning = false;
+        self.started_at = None;
+    }
+}
+
+struct Daemon {
+    config: config::Config,
+    device: Device,
+    scan_schedule: ScanSchedule,
+    get_lock_status: lock_status::LockStatusFn,
+    last_check: SystemTime,
+    /// The last unlock decision and its reason, so that each is logged and audited
+    /// once instead of on every tick.
+    last_decision: Option<(String, String)>,
+    was_locked: bool,
+    /// When the daemon last unlocked the session, to tell its unlocks from manual ones.
+    unlocked_at: Option<Instant>,
+    /// Whether the session is currently unlocked because of the daemon.
+    unlocked_by_daemon: bool,
+    rate_limiter: RateLimiter,
+    /// Whether scanning last worked, so that losing the adapter is reported once.
+    adapter_available: bool,
+    /// Whether the device was in range at the last reading; unknown until the first.
+    device_near: Option<bool>,
+    metrics: Arc<Metrics>,
+    mqtt: Option<Publisher>,
+}
+
+impl Daemon {
+    fn new(config: config::Config) -> Daemon {
+        let mut device = config.device.clone();
+        device.scan_options = config.scan_options();
+        let metrics = Arc::new(Metrics::default());
+        *metrics.device.lock().unwrap() = device.id.clone();
+        let mqtt = config.mqtt.as_ref().and_then(|settings| match Publisher::connect(settings, &device) {
+            Ok(publisher) => Some(publisher),
+            Err(e) => {
+                error!("Failed to set up MQTT, nothing will be published: {}", e);
+                None
+            }
+        });
+        Daemon {
+            device,
+            scan_schedule: ScanSchedule::new(&config.scan),
+            get_lock_status: lock_status::get_check_lock_func(),
+            last_check: SystemTime::now(),
+            last_decision: None,
+            was_locked: false,
+            unlocked_at: None,
+            unlocked_by_daemon: false,
+            rate_limiter: RateLimiter::default(),
+            adapter_available: true,
+            device_near: None,
+            metrics,
+            mqtt,
+            config,
+        }
+    }
+
+    async fn tick(&mut self) {
+        let now = SystemTime::now();
+        let elapsed = now.duration_since(self.last_check).unwrap_or(Duration::from_secs(0));
+        if elapsed > TICK_INTERVAL * 2 {
+            debug!("Detected system suspend or significant delay, re-initiating Bluetooth device search.");
+            self.restart_scan();
+        }
+        let locked = match (self.get_lock_status)().await {
+            Ok(locked) => locked,
+            Err(e) => {
+                debug!("Failed to get the lock status: {}", e);
+                self.metrics.dbus_errors.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        };
+        if self.was_locked && !locked {
+            self.on_unlocked().await;
+        } else if !self.was_locked && locked {
+            self.on_locked();
+        }
+        self.was_locked = locked;
+        // Probed on every tick: while the session is locked scanning never changes
+        // state, so its errors alone wouldn't tell.
+        let mut adapter = self.device.check_adapter().await.map_err(|e| e.to_string());
+        if adapter.is_ok() {
+            if let Some(active) = self.scan_schedule.update(locked, Instant::now()) {
+                debug!("{} scanning (session locked: {})", if active { "Starting" } else { "Stopping" }, locked);
+                if let Err(e) = self.device.set_scanning(active).await {
+                    self.restart_scan();
+                    adapter = Err(e.to_string());
+                }
+            }
+        }
+        self.update_adapter(adapter);
+        self.metrics.session_locked.store(locked, Ordering::Relaxed);
+        self.metrics.adapter_available.store(self.adapter_available, Ordering::Relaxed);
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.lock_changed(locked);
+        }
+        let Some(rssi) = self.read_rssi(locked).await else {
+            // Between scans nothing new is known about the device.
+            self.last_check = now;
+            return;
+        };
+        let in_range = self.config.is_in_range(&self.device);
+        self.metrics.rssi.store(i64::from(rssi), Ordering::Relaxed);
+        self.metrics.device_near.store(in_range, Ordering::Relaxed);
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.presence_changed(in_range, rssi);
+        }
+        if self.device_near.replace(in_range).is_some_and(|near| near != in_range) {
+            self.emit(if in_range { Event::DeviceNear { rssi } } else { Event::DeviceFar { rssi } });
+        }
+        if locked && self.config.unlock_mode == config::UnlockMode::Proximity {
+            if in_range {
+                if let Some(reason) = self.denial_reason().await {
+                    self.skip(reason);
+                } else if let Err(e) = unlock_session() {
+                    error!(
+                        bunlock_event = "error", bunlock_device = self.device.id.as_str(), bunlock_rssi = rssi;
+                        "Failed to unlock session: {}", e
+                    );
+                    self.audit("error", &format!("loginctl failed ({})", e));
+                } else {
+                    info!(
+                        bunlock_event = "unlock", bunlock_device = self.device.id.as_str(), bunlock_rssi = rssi;
+                        "System unlocked wtih {}", self.device.name
+                    );
+                    self.audit("unlock", "device in range");
+                    self.unlocked_at = Some(Instant::now());
+                    self.rate_limiter.unlocked(Instant::now());
+                    self.emit(Event::Unlocked { device: self.device.to_string(), rssi });
+                }
+            } else {
+                debug!("RSSI ({}) does not meet the unlocking criteria", rssi);
+                self.audit("deny", "device out of range");
+            }
+        }
+        self.last_check = now;
+    }
+
+    /// Reads the device's signal. While the session is unlocked, a reading is only
+    /// taken during the scan window and from advertisements, even in connection mode:
+    /// outside the window it would be stale, and a connection would defeat the power
+    /// saving. It then only looks among the devices already discovered instead of
+    /// waiting for the device to show up. Returns `None` when no reading was taken.
+    async fn read_rssi(&mut self, locked: bool) -> Option<i16> {
+        if !locked {
+            if !self.scan_schedule.scanning {
+                return None;
+            }
+            if !self.device.is_found() && !self.device.search_once().await.unwrap_or(false) {
+                self.device.rssi = i16::MIN;
+                return Some(self.device.rssi);
+            }
+            return Some(self.device.update_rssi().await);
+        }
+        Some(match self.config.presence_mode {
+            config::PresenceMode::Advertisement => self.device.update_rssi().await,
+            config::PresenceMode::Connection => self.device.update_connection_rssi().await,
+        })
+    }
+
+    /// Called when the session goes from locked to unlocked. An unlock the daemon
+    /// didn't do lifts a rate limit suspension. It isn't recorded as a password
+    /// authentication, since it may have been a fingerprint or the PAM module.
+    async fn on_unlocked(&mut self) {
+        let by_daemon = self
+            .unlocked_at
+            .is_some_and(|at| at.elapsed() <= TICK_INTERVAL * 2);
+        self.unlocked_by_daemon = by_daemon;
+        if !by_daemon {
+            debug!("Session unlocked manually");
+            self.rate_limiter.manual_unlock();
+        }
+        if self.config.presence_mode == config::PresenceMode::Connection {
+            // The connection is only kept while the session is locked.
+            self.device.disconnect().await;
+        }
+    }
+
+    /// Called when the session goes from unlocked to locked. Repeatedly locking a
+    /// session the daemon unlocked suggests someone fighting the lock.
+    fn on_locked(&mut self) {
+        self.emit(Event::Locked);
+        self.last_decision = None;
+        if !std::mem::take(&mut self.unlocked_by_daemon) {
+            return;
+        }
+        if self.rate_limiter.relocked(&self.config.rate_limit, Instant::now()) {
+            warn!(
+                bunlock_event = "suspended", bunlock_device = self.device.id.as_str();
+                "Session re-locked {} times within {} seconds, proximity unlock suspended until a manual unlock",
+                self.config.rate_limit.max_relocks,
+                self.config.rate_limit.relock_window_seconds
+            );
+        }
+    }
+
+    /// Returns why an unlock must not happen now even though the device is in range.
+    async fn denial_reason(&mut self) -> Option<String> {
+        match policy::denial_reason(&self.config, &mut self.rate_limiter, &auth::last_auth_path()).await {
+            Ok(reason) => reason,
+            Err(e) => {
+                self.metrics.dbus_errors.fetch_add(1, Ordering::Relaxed);
+                Some(format!("network state unknown ({})", e))
+            }
+        }
+    }
+
+    /// Logs, audits and emits a denial when its reason differs from the last decision.
+    fn skip(&mut self, reason: String) {
+        if self.audit("deny", &reason) {
+            info!(
+                bunlock_event = "deny",
+                bunlock_device = self.device.id.as_str(),
+                bunlock_rssi = self.device.rssi,
+                bunlock_reason = reason.as_str();
+                "Unlock skipped: {}", reason
+            );
+            self.emit(Event::UnlockDenied { reason });
+        }
+    }
+
+    /// Records an unlock decision with the readings it was based on. While the session
+    /// stays locked the same decision is only written once; returns whether it was new.
+    fn audit(&mut self, decision: &str, reason: &str) -> bool {
+        self.metrics.record_decision(decision);
+        let current = (decision.to_string(), reason.to_string());
+        if self.last_decision.as_ref() == Some(&current) {
+            return false;
+        }
+        self.last_decision = Some(current);
+        let record = audit::Record {
+            backend: Some(lock_status::backend_name()),
+            ..audit::Record::unlock(&self.config, &self.device, decision, reason)
+        };
+        if let Err(e) = audit::append(&audit::audit_log_path(), &record) {
+            error!("Failed to write the audit log: {}", e);
+        }
+        true
+    }
+
+    /// Reports the loss and the return of the adapter once each.
+    fn update_adapter(&mut self, adapter: Result<(), String>) {
+        match adapter {
+            Ok(()) if !self.adapter_available => {
+                info!(bunlock_event = "adapter_restored"; "Bluetooth adapter available again");
+                self.adapter_available = true;
+                // Discovery stopped with the adapter.
+                self.restart_scan();
+                self.emit(Event::AdapterRestored);
+            }
+            Ok(()) => {}
+            Err(e) if self.adapter_available => {
+                error!(bunlock_event = "adapter_lost"; "Bluetooth adapter unavailable: {}", e);
+                self.adapter_available = false;
+                self.emit(Event::AdapterLost { error: e });
+            }
+            Err(e) => debug!("Bluetooth adapter still unavailable: {}", e),
+        }
+    }
+
+    fn restart_scan(&mut self) {
+        self.metrics.scan_restarts.fetch_add(1, Ordering::Relaxed);
+        self.scan_schedule.restart();
+    }
+
+    /// Passes an event on to the notifications and hooks configured for it.
+    fn emit(&self, event: Event) {
+        debug!("Event {}: {}", event.name(), event);
+        if self.config.notifications.enabled_for(&event) {
+            notifications::show(&event);
+        }
+        hooks::run(&self.config.hooks, &event, &self.device.id, &self.device.name);
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.event(&event);
+        }
+    }
+
+    /// Loads the config file again and swaps it in. The tracked device (with its
+    /// peripheral and connection) is kept when its identity didn't change.
+    async fn reload(&mut self) {
+        // Read-only: migrating would rewrite the watched file and trigger another reload.
+        let new_config = match config::Config::load_read_only(&self.config.path) {
+            Ok(new_config) => new_config,
+            Err(e) => {
+                error!("Failed to reload config, keeping the current one: {}", e);
+                return;
+            }
+        };
+        let same_device = new_config.device.id == self.device.id
+            && new_config.device.match_rules == self.device.match_rules;
+        let was_connected = self.config.presence_mode == config::PresenceMode::Connection;
+        if was_connected && (!same_device || new_config.presence_mode != self.config.presence_mode) {
+            self.device.disconnect().await;
+        }
+        if same_device {
+            debug!("Device {} unchanged, keeping its tracking state", self.device);
+        } else {
+            info!("Switching to device {}", new_config.device);
+            self.device = new_config.device.clone();
+            *self.metrics.device.lock().unwrap() = self.device.id.clone();
+        }
+        let scan_options = new_config.scan_options();
+        if scan_options != self.device.scan_options {
+            self.device.scan_options = scan_options;
+            self.restart_scan();
+        }
+        self.scan_schedule.reconfigure(&new_config.scan);
+        if new_config.unlock_mode != self.config.unlock_mode {
+            info!("Switching to {} mode", new_config.unlock_mode);
+        }
+        if new_config.metrics != self.config.metrics {
+            warn!("Metrics endpoint settings changed, restart the daemon to apply them");
+        }
+        if new_config.mqtt != self.config.mqtt {
+            warn!("MQTT settings changed, restart the daemon to apply them");
+        }
+        self.config = new_config;
+        info!(bunlock_event = "reload"; "Config reloaded");
+    }
+
+    async fn shutdown(&mut self) {
+        if self.config.presence_mode == config::PresenceMode::Connection {
+            self.device.disconnect().await;
+        }
+        if let Some(mqtt) = self.mqtt.take() {
+            mqtt.disconnect().await;
+        }
+    }
+}
+
+/// Asks logind to unlock the session. A non-zero exit is a failure too.
+fn unlock_session() -> Result<(), Box<dyn Error>> {
+    let output = std::process::Command::new("loginctl").arg("unlock-session").output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("{}: {}", output.status, stderr.trim()).into());
+    }
+    Ok(())
+}
+
+async fn next_message<T>(receiver: &mut Option<UnboundedReceiver<T>>) -> Option<T> {
+    match receiver {
+        Some(receiver) => receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+pub async fn start_daemon(config_data: config::Config) -> Result<(), Box<dyn Error>> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sighup = signal(SignalKind::hangup())?;
+    let mut config_changes = match config::watcher::watch(&config_data.path) {
+        Ok(receiver) => Some(receiver),
+        Err(e) => {
+            error!("Failed to watch the config file, send SIGHUP to reload it: {}", e);
+            None
+        }
+    };
+    let mut approvals = match approval::serve() {
+        Ok(receiver) => Some(receiver),
+        Err(e) => {
+            error!("Failed to register {} on the session bus, approvals are unavailable: {}", approval::BUS_NAME, e);
+            None
+        }
+    };
+    let mut daemon = Daemon::new(config_data);
+    if let Some(settings) = &daemon.config.metrics {
+        if let Err(e) = metrics::serve(settings, daemon.metrics.clone()).await {
+            error!("Failed to serve metrics on {}: {}", settings.listen, e);
+        }
+    }
+    info!(bunlock_event = "start"; "Daemon started in {} mode", daemon.config.unlock_mode);
+    daemon.device.update_peripheral().await?;
+    loop {
+        daemon.tick().await;
+        tokio::select! {
+            _ = sigterm.recv() => {
+                debug!("Received SIGTERM, shutting down...");
+                break;
+            }
+            _ = sigint.recv() => {
+                debug!("Received SIGINT, shutting down...");
+                break;
+            }
+            _ = sighup.recv() => {
+                debug!("Received SIGHUP, reloading config...");
+                daemon.reload().await;
+            }
+            Some(()) = next_message(&mut config_changes) => {
+                sleep(RELOAD_SETTLE_DELAY).await;
+                if let Some(receiver) = &mut config_changes {
+                    while receiver.try_recv().is_ok() {}
+                }
+                debug!("Config file changed, reloading...");
+                daemon.reload().await;
+            }
+            Some(request) = next_message(&mut approvals) => {
+                approval::spawn_handler(request, daemon.config.clone());
+            }
+            _ = sleep(TICK_INTERVAL) => {}
+        }
+    }
+    daemon.shutdown().await;
+    info!(bunlock_event = "stop"; "Daemon shutting down");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schedule(start: Instant) -> ScanSchedule {
+        ScanSchedule {
+            window: Duration::from_secs(5),
+            interval: Duration::from_secs(60),
+            scanning: true,
+            started_at: Some(start),
+        }
+    }
+
+    fn secs(seconds: u64) -> Duration {
+        Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn scans_continuously_while_locked() {
+        let start = Instant::now();
+        let mut schedule = schedule(start);
+        for second in [0, 10, 100, 1000] {
+            assert_eq!(schedule.update(true, start + secs(second)), None);
+        }
+        assert!(schedule.scanning);
+    }
+
+    #[test]
+    fn duty_cycles_while_unlocked() {
+        let start = Instant::now();
+        let mut schedule = schedule(start);
+        assert_eq!(schedule.update(false, start + secs(4)), None);
+        assert_eq!(schedule.update(false, start + secs(5)), Some(false));
+        assert_eq!(schedule.update(false, start + secs(59)), None);
+        assert_eq!(schedule.update(false, start + secs(60)), Some(true));
+        assert_eq!(schedule.update(false, start + secs(64)), None);
+        assert_eq!(schedule.update(false, start + secs(65)), Some(false));
+    }
+
+    #[test]
+    fn locking_starts_scanning_at_once() {
+        let start = Instant::now();
+        let mut schedule = schedule(start);
+        schedule.update(false, start + secs(10));
+        assert_eq!(schedule.update(true, start + secs(11)), Some(true));
+        // Unlocking again opens a full window from the last locked update.
+        assert_eq!(schedule.update(false, start + secs(15)), None);
+        assert_eq!(schedule.update(false, start + secs(16)), Some(false));
+    }
+
+    #[test]
+    fn restart_starts_a_new_window() {
+        let start = Instant::now();
+        let mut schedule = schedule(start);
+        schedule.update(false, start + secs(10));
+        schedule.restart();
+        assert_eq!(schedule.update(false, start + secs(11)), Some(true));
+        assert_eq!(schedule.update(false, start + secs(16)), Some(false));
+    }
+}
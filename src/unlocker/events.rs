@@ -0,0 +1,81 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+use std::fmt;
+
+/// Something the daemon noticed that the user may want to hear about.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// The device came into range.
+    DeviceNear { rssi: i16 },
+    /// The device went out of range.
+    DeviceFar { rssi: i16 },
+    /// The daemon unlocked the session.
+    Unlocked { device: String, rssi: i16 },
+    /// The session was locked.
+    Locked,
+    /// The device was in range, but a policy blocked the unlock.
+    UnlockDenied { reason: String },
+    /// The Bluetooth adapter can't be used.
+    AdapterLost { error: String },
+    /// The Bluetooth adapter can be used again.
+    AdapterRestored,
+}
+
+impl Event {
+    /// Every event's [`name`](Event::name).
+    pub const NAMES: [&'static str; 7] = [
+        "device_near",
+        "device_far",
+        "unlocked",
+        "locked",
+        "unlock_denied",
+        "adapter_lost",
+        "adapter_restored",
+    ];
+
+    /// Short identifier, used in the config and for external consumers.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::DeviceNear { .. } => "device_near",
+            Event::DeviceFar { .. } => "device_far",
+            Event::Unlocked { .. } => "unlocked",
+            Event::Locked => "locked",
+            Event::UnlockDenied { .. } => "unlock_denied",
+            Event::AdapterLost { .. } => "adapter_lost",
+            Event::AdapterRestored => "adapter_restored",
+        }
+    }
+
+    /// The event's data as key-value pairs, for consumers that want it separately.
+    pub fn details(&self) -> Vec<(&'static str, String)> {
+        match self {
+            Event::DeviceNear { rssi } | Event::DeviceFar { rssi } | Event::Unlocked { rssi, .. } => {
+                vec![("rssi", rssi.to_string())]
+            }
+            Event::UnlockDenied { reason } => vec![("reason", reason.clone())],
+            Event::AdapterLost { error } => vec![("error", error.clone())],
+            Event::Locked | Event::AdapterRestored => vec![],
+        }
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::DeviceNear { rssi } => write!(f, "Device in range (RSSI {} dBm)", rssi),
+            Event::DeviceFar { rssi } => write!(f, "Device out of range (RSSI {} dBm)", rssi),
+            Event::Unlocked { device, rssi } => write!(f, "Unlocked with {} (RSSI {} dBm)", device, rssi),
+            Event::Locked => write!(f, "Session locked"),
+            Event::UnlockDenied { reason } => write!(f, "Unlock blocked: {}", reason),
+            Event::AdapterLost { error } => write!(f, "Bluetooth adapter unavailable: {}", error),
+            Event::AdapterRestored => write!(f, "Bluetooth adapter available again"),
+        }
+    }
+}
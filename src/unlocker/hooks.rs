@@ -0,0 +1,117 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::time::timeout;
+use crate::unlocker::events::Event;
+
+/// Shell commands to run on events. Each command gets the event in `BUNLOCK_EVENT`,
+/// the device in `BUNLOCK_DEVICE_ID` and `BUNLOCK_DEVICE_NAME`, and the event's
+/// details in `BUNLOCK_<DETAIL>` variables, e.g. `BUNLOCK_RSSI`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct HookSettings {
+    /// Commands still running after this long are killed.
+    pub timeout_seconds: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub device_near: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub device_far: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unlocked: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub locked: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unlock_denied: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub adapter_lost: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub adapter_restored: Vec<String>,
+}
+
+impl Default for HookSettings {
+    fn default() -> Self {
+        HookSettings {
+            timeout_seconds: 10,
+            device_near: vec![],
+            device_far: vec![],
+            unlocked: vec![],
+            locked: vec![],
+            unlock_denied: vec![],
+            adapter_lost: vec![],
+            adapter_restored: vec![],
+        }
+    }
+}
+
+impl HookSettings {
+    pub fn commands_for(&self, event: &Event) -> &[String] {
+        match event {
+            Event::DeviceNear { .. } => &self.device_near,
+            Event::DeviceFar { .. } => &self.device_far,
+            Event::Unlocked { .. } => &self.unlocked,
+            Event::Locked => &self.locked,
+            Event::UnlockDenied { .. } => &self.unlock_denied,
+            Event::AdapterLost { .. } => &self.adapter_lost,
+            Event::AdapterRestored => &self.adapter_restored,
+        }
+    }
+}
+
+/// Starts the commands configured for the event in the background.
+pub fn run(settings: &HookSettings, event: &Event, device_id: &str, device_name: &str) {
+    let mut env = vec![
+        ("BUNLOCK_EVENT".to_string(), event.name().to_string()),
+        ("BUNLOCK_DEVICE_ID".to_string(), device_id.to_string()),
+        ("BUNLOCK_DEVICE_NAME".to_string(), device_name.to_string()),
+    ];
+    for (key, value) in event.details() {
+        env.push((format!("BUNLOCK_{}", key.to_uppercase()), value));
+    }
+    let max_duration = Duration::from_secs(settings.timeout_seconds);
+    for command in settings.commands_for(event) {
+        tokio::spawn(run_command(command.clone(), env.clone(), max_duration));
+    }
+}
+
+async fn run_command(command: String, env: Vec<(String, String)>, max_duration: Duration) {
+    debug!("Running hook: {}", command);
+    let child = Command::new("sh")
+        .arg("-c")
+        .arg(&command)
+        .envs(env)
+        .stdin(Stdio::null())
+        // Its own process group, so that everything it started can be killed.
+        .process_group(0)
+        .kill_on_drop(true)
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) => {
+            error!("Failed to run hook \"{}\": {}", command, e);
+            return;
+        }
+    };
+    match timeout(max_duration, child.wait()).await {
+        Ok(Ok(status)) if status.success() => {}
+        Ok(Ok(status)) => warn!("Hook \"{}\" failed with {}", command, status),
+        Ok(Err(e)) => error!("Failed to wait for hook \"{}\": {}", command, e),
+        Err(_) => {
+            warn!("Hook \"{}\" timed out after {} seconds, killing it", command, max_duration.as_secs());
+            if let Some(pid) = child.id() {
+                unsafe { libc::killpg(pid as libc::pid_t, libc::SIGKILL) };
+            }
+            child.kill().await.ok();
+        }
+    }
+}
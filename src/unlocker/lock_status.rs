@@ -14,11 +14,15 @@ use std::error::Error;
 use std::future::Future;
 use std::pin::Pin;
 
-type LockStatusFn = fn() -> Pin<Box<dyn Future<Output = Result<bool, Box<dyn Error>>>>>;
+pub type LockStatusFn = fn() -> Pin<Box<dyn Future<Output = Result<bool, Box<dyn Error>>>>>;
+
+/// The backend that is used, as named in `XDG_CURRENT_DESKTOP`.
+pub fn backend_name() -> String {
+    std::env::var("XDG_CURRENT_DESKTOP").unwrap_or_default()
+}
 
 pub fn get_check_lock_func() -> LockStatusFn {
-    let desktop_env = std::env::var("XDG_CURRENT_DESKTOP").unwrap_or_default();
-    match desktop_env.as_str() {
+    match backend_name().as_str() {
         "GNOME" => || Box::pin(get_lock_status_gnome()),
         "KDE" => || Box::pin(get_lock_status_kde()),
         "XFCE" => || Box::pin(get_lock_status_xfce()),
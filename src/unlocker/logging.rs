@@ -0,0 +1,45 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+use log::LevelFilter;
+use systemd_journal_logger::JournalLog;
+
+const SYSLOG_IDENTIFIER: &str = "bunlock";
+
+/// Logs to the journal when run by systemd, so that the structured fields of log
+/// lines (`BUNLOCK_EVENT`, `BUNLOCK_DEVICE`, ...) can be queried, and to stderr with
+/// env_logger otherwise. In the journal the level is taken from `RUST_LOG` when it
+/// is a plain level such as `info` or `debug`.
+pub fn init() {
+    if systemd_journal_logger::connected_to_journal() {
+        let installed = JournalLog::new()
+            .map_err(|e| e.to_string())
+            .and_then(|journal| {
+                journal
+                    .with_syslog_identifier(SYSLOG_IDENTIFIER.to_string())
+                    .install()
+                    .map_err(|e| e.to_string())
+            });
+        match installed {
+            Ok(()) => {
+                log::set_max_level(journal_level());
+                return;
+            }
+            Err(e) => eprintln!("Failed to log to the journal, logging to stderr: {}", e),
+        }
+    }
+    env_logger::init();
+}
+
+fn journal_level() -> LevelFilter {
+    std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(LevelFilter::Info)
+}
@@ -0,0 +1,109 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+use chrono::{DateTime, Local};
+use serde_json::Value;
+use std::error::Error;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Command, Stdio};
+use crate::unlocker::service;
+
+/// Values of `BUNLOCK_EVENT` that record an unlock decision.
+const DECISION_EVENTS: [&str; 5] = ["unlock", "deny", "error", "suspended", "approval"];
+/// `--level` names and the matching journalctl priorities.
+pub const LEVELS: [(&str, &str); 4] = [("error", "err"), ("warn", "warning"), ("info", "info"), ("debug", "debug")];
+
+#[derive(Debug, Default)]
+pub struct LogOptions {
+    pub follow: bool,
+    /// Anything `journalctl --since` accepts, e.g. `today` or `1 hour ago`.
+    pub since: Option<String>,
+    /// One of the names in [`LEVELS`]; messages of lower importance are hidden.
+    pub level: Option<String>,
+    pub lines: Option<usize>,
+    /// Show only unlock decisions.
+    pub decisions: bool,
+}
+
+/// Reads the service's entries from the user journal and prints them one per line.
+pub fn print(options: &LogOptions) -> Result<(), Box<dyn Error>> {
+    let mut command = Command::new("journalctl");
+    command.args(["--user", "-u", service::SERVICE_NAME, "-o", "json", "--no-pager"]);
+    if options.follow {
+        command.arg("--follow");
+    }
+    if let Some(since) = &options.since {
+        command.args(["--since", since]);
+    }
+    if let Some(level) = &options.level {
+        let (_, priority) = LEVELS.iter().find(|(name, _)| name == level).ok_or("Unknown level")?;
+        command.args(["--priority", priority]);
+    }
+    if let Some(lines) = options.lines {
+        command.args(["--lines", &lines.to_string()]);
+    }
+    if options.decisions {
+        // Matches on the same field are combined with OR.
+        command.args(DECISION_EVENTS.iter().map(|event| format!("BUNLOCK_EVENT={}", event)));
+    }
+    let mut child = command.stdout(Stdio::piped()).spawn()?;
+    let stdout = child.stdout.take().ok_or("Failed to read journalctl output")?;
+    let mut out = std::io::stdout().lock();
+    for line in BufReader::new(stdout).lines() {
+        let Ok(entry) = serde_json::from_str::<Value>(&line?) else {
+            continue;
+        };
+        if writeln!(out, "{}", format_entry(&entry)).is_err() {
+            // The reader went away, e.g. `bunlock logs | head`.
+            child.kill().ok();
+            break;
+        }
+    }
+    let status = child.wait()?;
+    if !status.success() && status.code().is_some() {
+        return Err(format!("journalctl failed with {}", status).into());
+    }
+    Ok(())
+}
+
+/// `time level [event] message`, followed by the RSSI when the entry has one.
+fn format_entry(entry: &Value) -> String {
+    let time = field(entry, "__REALTIME_TIMESTAMP")
+        .and_then(|micros| micros.parse::<i64>().ok())
+        .and_then(DateTime::from_timestamp_micros)
+        .map(|time| time.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_default();
+    let level = match field(entry, "PRIORITY").as_deref() {
+        Some("0" | "1" | "2" | "3") => "ERROR",
+        Some("4") => "WARN",
+        Some("5" | "6") => "INFO",
+        _ => "DEBUG",
+    };
+    let mut line = format!("{} {:<5}", time, level);
+    if let Some(event) = field(entry, "BUNLOCK_EVENT") {
+        line.push_str(&format!(" [{}]", event));
+    }
+    line.push_str(&format!(" {}", field(entry, "MESSAGE").unwrap_or_default()));
+    if let Some(rssi) = field(entry, "BUNLOCK_RSSI") {
+        line.push_str(&format!(" (RSSI {})", rssi));
+    }
+    line
+}
+
+/// A journal field as text. Fields that aren't valid UTF-8 come as byte arrays.
+fn field(entry: &Value, name: &str) -> Option<String> {
+    match entry.get(name)? {
+        Value::String(value) => Some(value.clone()),
+        Value::Array(bytes) => {
+            let bytes: Vec<u8> = bytes.iter().filter_map(|b| b.as_u64().map(|b| b as u8)).collect();
+            Some(String::from_utf8_lossy(&bytes).into_owned())
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,236 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::time::{timeout, Duration};
+
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+/// A client that doesn't send its request within this time is disconnected.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Where the daemon serves its metrics in the Prometheus text format.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct MetricsSettings {
+    /// `host:port` for HTTP over TCP, or an absolute path for a Unix socket.
+    pub listen: String,
+}
+
+impl Default for MetricsSettings {
+    fn default() -> Self {
+        MetricsSettings { listen: "127.0.0.1:9877".to_string() }
+    }
+}
+
+/// Counters and gauges the daemon updates as it runs.
+#[derive(Debug)]
+pub struct Metrics {
+    pub device: Mutex<String>,
+    /// Last RSSI reading, `i16::MIN` when the device wasn't found.
+    pub rssi: AtomicI64,
+    pub device_near: AtomicBool,
+    pub session_locked: AtomicBool,
+    pub adapter_available: AtomicBool,
+    pub unlocks: AtomicU64,
+    pub denials: AtomicU64,
+    pub unlock_errors: AtomicU64,
+    pub scan_restarts: AtomicU64,
+    pub dbus_errors: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics {
+            device: Mutex::new(String::new()),
+            // No reading yet.
+            rssi: AtomicI64::new(i64::from(i16::MIN)),
+            device_near: AtomicBool::new(false),
+            session_locked: AtomicBool::new(false),
+            adapter_available: AtomicBool::new(false),
+            unlocks: AtomicU64::new(0),
+            denials: AtomicU64::new(0),
+            unlock_errors: AtomicU64::new(0),
+            scan_restarts: AtomicU64::new(0),
+            dbus_errors: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    /// Counts an unlock decision as recorded in the audit log.
+    pub fn record_decision(&self, decision: &str) {
+        let counter = match decision {
+            "unlock" => &self.unlocks,
+            "deny" => &self.denials,
+            _ => &self.unlock_errors,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let device = self.device.lock().unwrap().replace('\\', "\\\\").replace('"', "\\\"");
+        let mut out = String::new();
+        let mut gauge = |name: &str, help: &str, value: String| {
+            writeln!(out, "# HELP {} {}", name, help).ok();
+            writeln!(out, "# TYPE {} gauge", name).ok();
+            writeln!(out, "{}{{device=\"{}\"}} {}", name, device, value).ok();
+        };
+        let rssi = self.rssi.load(Ordering::Relaxed);
+        let rssi = if rssi == i64::from(i16::MIN) { "NaN".to_string() } else { rssi.to_string() };
+        gauge("bunlock_rssi_dbm", "Last RSSI of the trusted device, NaN when not found.", rssi);
+        gauge("bunlock_device_near", "Whether the trusted device is in range.", flag(&self.device_near));
+        gauge("bunlock_session_locked", "Whether the session is locked.", flag(&self.session_locked));
+        gauge(
+            "bunlock_adapter_available",
+            "Whether the Bluetooth adapter can be used.",
+            flag(&self.adapter_available),
+        );
+
+        writeln!(out, "# HELP bunlock_unlock_decisions_total Unlock decisions by outcome.").ok();
+        writeln!(out, "# TYPE bunlock_unlock_decisions_total counter").ok();
+        for (outcome, counter) in [("unlock", &self.unlocks), ("deny", &self.denials), ("error", &self.unlock_errors)] {
+            writeln!(
+                out,
+                "bunlock_unlock_decisions_total{{outcome=\"{}\"}} {}",
+                outcome,
+                counter.load(Ordering::Relaxed)
+            )
+            .ok();
+        }
+        let mut counter = |name: &str, help: &str, value: &AtomicU64| {
+            writeln!(out, "# HELP {} {}", name, help).ok();
+            writeln!(out, "# TYPE {} counter", name).ok();
+            writeln!(out, "{} {}", name, value.load(Ordering::Relaxed)).ok();
+        };
+        counter("bunlock_scan_restarts_total", "Times scanning was restarted after an error, suspend or settings change.", &self.scan_restarts);
+        counter("bunlock_dbus_errors_total", "Failed D-Bus queries of the lock status and network.", &self.dbus_errors);
+        out
+    }
+}
+
+fn flag(value: &AtomicBool) -> String {
+    u8::from(value.load(Ordering::Relaxed)).to_string()
+}
+
+/// Starts serving the metrics in the background. Any request path is answered with
+/// the metrics, so both `/` and `/metrics` work.
+pub async fn serve(settings: &MetricsSettings, metrics: Arc<Metrics>) -> Result<(), Box<dyn Error>> {
+    if settings.listen.starts_with('/') {
+        let path = Path::new(&settings.listen);
+        match std::fs::symlink_metadata(path) {
+            // Left behind by a previous run.
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path)?,
+            Ok(_) => return Err(format!("{} exists and is not a socket", path.display()).into()),
+            Err(_) => {}
+        }
+        // Only the user may connect: the socket is created with mode 0600.
+        let umask = unsafe { libc::umask(0o177) };
+        let listener = UnixListener::bind(path);
+        unsafe { libc::umask(umask) };
+        let listener = listener?;
+        debug!("Serving metrics on {}", path.display());
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(respond(stream, metrics.clone()));
+                    }
+                    Err(e) => error!("Failed to accept metrics connection: {}", e),
+                }
+            }
+        });
+    } else {
+        let address: SocketAddr = settings.listen.parse()?;
+        let listener = TcpListener::bind(address).await?;
+        debug!("Serving metrics on http://{}/metrics", address);
+        tokio::spawn(async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => {
+                        tokio::spawn(respond(stream, metrics.clone()));
+                    }
+                    Err(e) => error!("Failed to accept metrics connection: {}", e),
+                }
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Reads the request head and answers with the metrics. Only `GET` is supported.
+async fn respond<S: AsyncRead + AsyncWrite + Unpin>(mut stream: S, metrics: Arc<Metrics>) {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    let read = async {
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+            match stream.read(&mut buffer).await {
+                Ok(0) | Err(_) => break,
+                Ok(read) => request.extend_from_slice(&buffer[..read]),
+            }
+        }
+    };
+    if timeout(READ_TIMEOUT, read).await.is_err() {
+        debug!("Metrics client didn't send a request in time");
+        return;
+    }
+    let response = if request.starts_with(b"GET ") {
+        let body = metrics.render();
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            CONTENT_TYPE,
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 405 Method Not Allowed\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string()
+    };
+    stream.write_all(response.as_bytes()).await.ok();
+    stream.shutdown().await.ok();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_gauges_and_counters() {
+        let metrics = Metrics::default();
+        *metrics.device.lock().unwrap() = "AA:BB \"phone\"".to_string();
+        metrics.rssi.store(-60, Ordering::Relaxed);
+        metrics.device_near.store(true, Ordering::Relaxed);
+        metrics.record_decision("unlock");
+        metrics.record_decision("deny");
+        metrics.record_decision("deny");
+        metrics.record_decision("error");
+        let text = metrics.render();
+        assert!(text.contains("bunlock_rssi_dbm{device=\"AA:BB \\\"phone\\\"\"} -60\n"));
+        assert!(text.contains("bunlock_device_near{device=\"AA:BB \\\"phone\\\"\"} 1\n"));
+        assert!(text.contains("bunlock_session_locked{device=\"AA:BB \\\"phone\\\"\"} 0\n"));
+        assert!(text.contains("bunlock_unlock_decisions_total{outcome=\"unlock\"} 1\n"));
+        assert!(text.contains("bunlock_unlock_decisions_total{outcome=\"deny\"} 2\n"));
+        assert!(text.contains("bunlock_unlock_decisions_total{outcome=\"error\"} 1\n"));
+        assert!(text.contains("# TYPE bunlock_scan_restarts_total counter\nbunlock_scan_restarts_total 0\n"));
+    }
+
+    #[test]
+    fn renders_missing_rssi_as_nan() {
+        let metrics = Metrics::default();
+        assert!(metrics.render().contains("bunlock_rssi_dbm{device=\"\"} NaN\n"));
+    }
+}
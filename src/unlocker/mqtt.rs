@@ -0,0 +1,428 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+use log::{debug, info, warn};
+use rumqttc::tokio_rustls::rustls::{ClientConfig, RootCertStore};
+use rumqttc::{
+    AsyncClient, Event as MqttEvent, EventLoop, LastWill, MqttOptions, Outgoing, Packet, QoS, TlsConfiguration, Transport,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::error::Error;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
+use crate::unlocker::bluetooth::device::Device;
+use crate::unlocker::events::Event;
+
+const KEEP_ALIVE: Duration = Duration::from_secs(30);
+/// How long to wait before reconnecting after the connection failed.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// RSSI changes all the time; publishing every reading would flood the broker.
+const RSSI_INTERVAL: Duration = Duration::from_secs(30);
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+/// Publishing of the presence state and events to an MQTT broker.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct MqttSettings {
+    pub host: String,
+    pub port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// Whether to connect over TLS, verifying the broker against `ca_file` or the
+    /// system's certificates.
+    pub tls: bool,
+    /// PEM file with the CA certificates of the broker.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ca_file: Option<PathBuf>,
+    /// Defaults to `bunlock-<hostname>`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    /// Prefix of the state topics. Defaults to `bunlock/<hostname>`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub topic: Option<String>,
+    /// Whether to publish Home Assistant discovery payloads.
+    pub discovery: bool,
+    pub discovery_prefix: String,
+}
+
+impl Default for MqttSettings {
+    fn default() -> Self {
+        MqttSettings {
+            host: "localhost".to_string(),
+            port: 1883,
+            username: None,
+            password: None,
+            tls: false,
+            ca_file: None,
+            client_id: None,
+            topic: None,
+            discovery: true,
+            discovery_prefix: "homeassistant".to_string(),
+        }
+    }
+}
+
+impl MqttSettings {
+    pub fn client_id(&self) -> String {
+        self.client_id.clone().unwrap_or_else(|| format!("bunlock-{}", hostname()))
+    }
+
+    pub fn topic(&self) -> String {
+        self.topic.clone().unwrap_or_else(|| format!("bunlock/{}", hostname()))
+    }
+
+    /// Whether the broker runs on this machine, where a plain connection is private.
+    pub fn is_local(&self) -> bool {
+        self.host == "localhost" || self.host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+    }
+
+    fn tls_configuration(&self) -> Result<TlsConfiguration, Box<dyn Error>> {
+        if let Some(ca_file) = &self.ca_file {
+            let ca = std::fs::read(ca_file).map_err(|e| format!("Failed to read {}: {}", ca_file.display(), e))?;
+            return Ok(TlsConfiguration::Simple { ca, alpn: None, client_auth: None });
+        }
+        let certificates = rustls_native_certs::load_native_certs();
+        for e in &certificates.errors {
+            warn!("Failed to load system certificates: {}", e);
+        }
+        let mut roots = RootCertStore::empty();
+        roots.add_parsable_certificates(certificates.certs);
+        if roots.is_empty() {
+            return Err("No system certificates found, set mqtt.ca_file".into());
+        }
+        Ok(ClientConfig::builder().with_root_certificates(roots).with_no_client_auth().into())
+    }
+}
+
+/// What was last published, so that it can be published again after reconnecting.
+#[derive(Debug, Default)]
+struct State {
+    near: Option<bool>,
+    locked: Option<bool>,
+    rssi: Option<i16>,
+    rssi_published_at: Option<Instant>,
+}
+
+/// Topics and discovery payloads, shared between the daemon and the event loop.
+struct Topics {
+    base: String,
+    discovery: Vec<(String, String)>,
+}
+
+impl Topics {
+    fn state(&self, name: &str) -> String {
+        format!("{}/{}", self.base, name)
+    }
+}
+
+pub struct Publisher {
+    client: AsyncClient,
+    topics: Arc<Topics>,
+    state: Arc<Mutex<State>>,
+    event_loop: JoinHandle<()>,
+}
+
+impl Publisher {
+    /// Starts connecting to the broker in the background. The connection is retried
+    /// until the daemon stops; publishing never waits for it.
+    pub fn connect(settings: &MqttSettings, device: &Device) -> Result<Publisher, Box<dyn Error>> {
+        let base = settings.topic();
+        let topics = Arc::new(Topics {
+            discovery: if settings.discovery { discovery_payloads(settings, &base, device) } else { vec![] },
+            base,
+        });
+        let mut options = MqttOptions::new(settings.client_id(), &settings.host, settings.port);
+        options.set_keep_alive(KEEP_ALIVE);
+        if settings.tls {
+            options.set_transport(Transport::tls_with_config(settings.tls_configuration()?));
+        }
+        options.set_last_will(LastWill::new(topics.state("availability"), OFFLINE, QoS::AtLeastOnce, true));
+        if let Some(username) = &settings.username {
+            options.set_credentials(username, settings.password.as_deref().unwrap_or_default());
+        }
+        let (client, event_loop) = AsyncClient::new(options, 32);
+        let state = Arc::new(Mutex::new(State::default()));
+        let event_loop = tokio::spawn(run(
+            event_loop,
+            client.clone(),
+            topics.clone(),
+            state.clone(),
+            format!("{}:{}", settings.host, settings.port),
+        ));
+        Ok(Publisher { client, topics, state, event_loop })
+    }
+
+    /// Publishes the lock state if it changed.
+    pub fn lock_changed(&self, locked: bool) {
+        let mut state = self.state.lock().unwrap();
+        if state.locked.replace(locked) != Some(locked) {
+            self.publish(&self.topics.state("lock"), lock_payload(locked), true);
+        }
+    }
+
+    /// Publishes the presence if it changed. The RSSI is published at most every
+    /// [`RSSI_INTERVAL`], or along with a presence change.
+    pub fn presence_changed(&self, near: bool, rssi: i16) {
+        let mut state = self.state.lock().unwrap();
+        let near_changed = state.near.replace(near) != Some(near);
+        if near_changed {
+            self.publish(&self.topics.state("presence"), presence_payload(near), true);
+        }
+        let rssi_due = state.rssi_published_at.is_none_or(|at| at.elapsed() >= RSSI_INTERVAL);
+        if state.rssi != Some(rssi) && (near_changed || rssi_due) {
+            state.rssi = Some(rssi);
+            state.rssi_published_at = Some(Instant::now());
+            self.publish(&self.topics.state("rssi"), &rssi_payload(rssi), true);
+        }
+    }
+
+    pub fn event(&self, event: &Event) {
+        self.publish(&self.topics.state("event"), &event_payload(event), false);
+    }
+
+    /// Marks the daemon offline and closes the connection, waiting briefly for both
+    /// to be sent.
+    pub async fn disconnect(self) {
+        self.publish(&self.topics.state("availability"), OFFLINE, true);
+        if self.client.try_disconnect().is_ok() {
+            timeout(Duration::from_secs(2), self.event_loop).await.ok();
+        }
+    }
+
+    fn publish(&self, topic: &str, payload: &str, retain: bool) {
+        if let Err(e) = self.client.try_publish(topic, QoS::AtLeastOnce, retain, payload) {
+            debug!("Dropped MQTT message for {}: {}", topic, e);
+        }
+    }
+}
+
+/// Drives the connection. After every (re)connect the availability, the discovery
+/// payloads and the current state are published again.
+async fn run(mut event_loop: EventLoop, client: AsyncClient, topics: Arc<Topics>, state: Arc<Mutex<State>>, broker: String) {
+    let mut connected = true;
+    loop {
+        match event_loop.poll().await {
+            Ok(MqttEvent::Incoming(Packet::ConnAck(_))) => {
+                info!("Connected to MQTT broker {}", broker);
+                connected = true;
+                let mut messages = vec![(topics.state("availability"), ONLINE.to_string())];
+                messages.extend(topics.discovery.iter().cloned());
+                let state = state.lock().unwrap();
+                if let Some(near) = state.near {
+                    messages.push((topics.state("presence"), presence_payload(near).to_string()));
+                }
+                if let Some(locked) = state.locked {
+                    messages.push((topics.state("lock"), lock_payload(locked).to_string()));
+                }
+                if let Some(rssi) = state.rssi {
+                    messages.push((topics.state("rssi"), rssi_payload(rssi)));
+                }
+                for (topic, payload) in messages {
+                    if let Err(e) = client.try_publish(&topic, QoS::AtLeastOnce, true, payload) {
+                        debug!("Dropped MQTT message for {}: {}", topic, e);
+                    }
+                }
+            }
+            Ok(MqttEvent::Outgoing(Outgoing::Disconnect)) => break,
+            Ok(_) => {}
+            Err(e) => {
+                if std::mem::replace(&mut connected, false) {
+                    warn!("MQTT connection to {} failed, retrying: {}", broker, e);
+                } else {
+                    debug!("MQTT connection to {} failed: {}", broker, e);
+                }
+                sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
+
+/// The event's details are kept apart from the fields Home Assistant reads.
+fn event_payload(event: &Event) -> String {
+    let details: Map<String, Value> = event
+        .details()
+        .into_iter()
+        .map(|(key, value)| (key.to_string(), Value::String(value)))
+        .collect();
+    json!({ "event_type": event.name(), "message": event.to_string(), "details": details }).to_string()
+}
+
+fn presence_payload(near: bool) -> &'static str {
+    if near { "near" } else { "far" }
+}
+
+fn lock_payload(locked: bool) -> &'static str {
+    if locked { "locked" } else { "unlocked" }
+}
+
+/// Empty when the device wasn't found, which Home Assistant shows as unknown.
+fn rssi_payload(rssi: i16) -> String {
+    if rssi == i16::MIN { String::new() } else { rssi.to_string() }
+}
+
+/// Home Assistant MQTT discovery messages: topic and retained config payload for
+/// each entity.
+fn discovery_payloads(settings: &MqttSettings, base: &str, device: &Device) -> Vec<(String, String)> {
+    let node_id = settings
+        .client_id()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect::<String>();
+    let ha_device = json!({
+        "identifiers": [node_id],
+        "name": format!("BUnlock {}", hostname()),
+        "manufacturer": "BUnlock",
+        "model": format!("Trusted device {}", device),
+        "sw_version": env!("CARGO_PKG_VERSION"),
+    });
+    let entities = [
+        (
+            "binary_sensor",
+            "presence",
+            json!({
+                "name": "Presence",
+                "state_topic": format!("{}/presence", base),
+                "payload_on": "near",
+                "payload_off": "far",
+                "device_class": "presence",
+            }),
+        ),
+        (
+            "binary_sensor",
+            "lock",
+            json!({
+                "name": "Session",
+                "state_topic": format!("{}/lock", base),
+                // The lock device class is on when unlocked.
+                "payload_on": "unlocked",
+                "payload_off": "locked",
+                "device_class": "lock",
+            }),
+        ),
+        (
+            "sensor",
+            "rssi",
+            json!({
+                "name": "RSSI",
+                "state_topic": format!("{}/rssi", base),
+                "unit_of_measurement": "dBm",
+                "device_class": "signal_strength",
+                "state_class": "measurement",
+                "entity_category": "diagnostic",
+            }),
+        ),
+        (
+            "event",
+            "event",
+            json!({
+                "name": "Event",
+                "state_topic": format!("{}/event", base),
+                "event_types": Event::NAMES,
+            }),
+        ),
+    ];
+    entities
+        .into_iter()
+        .map(|(component, object_id, mut config)| {
+            config["unique_id"] = json!(format!("{}_{}", node_id, object_id));
+            config["availability_topic"] = json!(format!("{}/availability", base));
+            config["device"] = ha_device.clone();
+            (
+                format!("{}/{}/{}/{}/config", settings.discovery_prefix, component, node_id, object_id),
+                config.to_string(),
+            )
+        })
+        .collect()
+}
+
+fn hostname() -> String {
+    let mut buffer = [0u8; 256];
+    let result = unsafe { libc::gethostname(buffer.as_mut_ptr() as *mut libc::c_char, buffer.len()) };
+    if result != 0 {
+        return "localhost".to_string();
+    }
+    let end = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    String::from_utf8_lossy(&buffer[..end]).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn event_details_are_namespaced() {
+        let payload: Value = serde_json::from_str(&event_payload(&Event::DeviceNear { rssi: -50 })).unwrap();
+        assert_eq!(payload["event_type"], "device_near");
+        assert_eq!(payload["details"]["rssi"], "-50");
+        assert_eq!(payload.as_object().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn discovery_payloads_describe_each_entity() {
+        let settings = MqttSettings { client_id: Some("bunlock-my.host".to_string()), ..MqttSettings::default() };
+        let mut device = Device::default();
+        device.id = "AA:BB:CC:DD:EE:FF".to_string();
+        device.name = "Phone".to_string();
+        let payloads = discovery_payloads(&settings, "bunlock/desk", &device);
+        let topics: Vec<&str> = payloads.iter().map(|(topic, _)| topic.as_str()).collect();
+        assert_eq!(
+            topics,
+            [
+                "homeassistant/binary_sensor/bunlock_my_host/presence/config",
+                "homeassistant/binary_sensor/bunlock_my_host/lock/config",
+                "homeassistant/sensor/bunlock_my_host/rssi/config",
+                "homeassistant/event/bunlock_my_host/event/config",
+            ]
+        );
+        let configs: Vec<Value> = payloads.iter().map(|(_, payload)| serde_json::from_str(payload).unwrap()).collect();
+        for config in &configs {
+            assert_eq!(config["availability_topic"], "bunlock/desk/availability");
+            assert_eq!(config["device"]["identifiers"], json!(["bunlock_my_host"]));
+        }
+        assert_eq!(configs[0]["state_topic"], "bunlock/desk/presence");
+        assert_eq!(configs[0]["unique_id"], "bunlock_my_host_presence");
+        assert_eq!(configs[1]["payload_on"], "unlocked");
+        assert_eq!(configs[2]["unit_of_measurement"], "dBm");
+        assert_eq!(configs[3]["event_types"], json!(Event::NAMES));
+    }
+
+    #[test]
+    fn rssi_of_a_missing_device_is_empty() {
+        assert_eq!(rssi_payload(-60), "-60");
+        assert_eq!(rssi_payload(i16::MIN), "");
+    }
+
+    #[test]
+    fn recognizes_local_brokers() {
+        let local = |host: &str| MqttSettings { host: host.to_string(), ..MqttSettings::default() }.is_local();
+        assert!(local("localhost"));
+        assert!(local("127.0.0.1"));
+        assert!(local("::1"));
+        assert!(!local("192.168.1.10"));
+        assert!(!local("broker.example.com"));
+    }
+
+    #[test]
+    fn missing_ca_file_is_an_error() {
+        let settings = MqttSettings {
+            tls: true,
+            ca_file: Some(PathBuf::from("/nonexistent/ca.pem")),
+            ..MqttSettings::default()
+        };
+        assert!(settings.tls_configuration().is_err());
+    }
+}
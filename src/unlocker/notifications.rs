@@ -0,0 +1,146 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+use dbus::arg::{PropMap, Variant};
+use dbus::blocking::{Connection, Proxy};
+use dbus::message::MatchRule;
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::unlocker::events::Event;
+
+const NOTIFY_DEST: &str = "org.freedesktop.Notifications";
+const NOTIFY_PATH: &str = "/org/freedesktop/Notifications";
+const NOTIFY_IFACE: &str = "org.freedesktop.Notifications";
+const BUS_DEST: &str = "org.freedesktop.DBus";
+const BUS_PATH: &str = "/org/freedesktop/DBus";
+const APP_NAME: &str = "BUnlock";
+const ICON: &str = "bluetooth";
+const DBUS_TIMEOUT: Duration = Duration::from_secs(2);
+const URGENCY_NORMAL: u8 = 1;
+const URGENCY_CRITICAL: u8 = 2;
+/// Let the notification server decide how long to show event notifications.
+const DEFAULT_EXPIRY: i32 = -1;
+const ALLOW_ACTION: &str = "allow";
+const DENY_ACTION: &str = "deny";
+
+/// Which events are shown as desktop notifications. All are off by default.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct NotificationSettings {
+    pub unlocked: bool,
+    pub locked: bool,
+    pub unlock_denied: bool,
+    /// Loss and return of the Bluetooth adapter.
+    pub adapter: bool,
+}
+
+impl NotificationSettings {
+    pub fn enabled_for(&self, event: &Event) -> bool {
+        match event {
+            Event::Unlocked { .. } => self.unlocked,
+            Event::Locked => self.locked,
+            Event::UnlockDenied { .. } => self.unlock_denied,
+            Event::AdapterLost { .. } | Event::AdapterRestored => self.adapter,
+            Event::DeviceNear { .. } | Event::DeviceFar { .. } => false,
+        }
+    }
+}
+
+/// Shows a notification for the event on a separate thread, so that a slow
+/// notification server doesn't hold up the daemon.
+pub fn show(event: &Event) {
+    let (summary, body) = match event {
+        Event::DeviceNear { .. } | Event::DeviceFar { .. } => (event.to_string(), String::new()),
+        Event::Unlocked { device, rssi } => ("Session unlocked".to_string(), format!("{} is nearby (RSSI {} dBm)", device, rssi)),
+        Event::Locked => ("Session locked".to_string(), String::new()),
+        Event::UnlockDenied { reason } => ("Proximity unlock blocked".to_string(), reason.clone()),
+        Event::AdapterLost { error } => ("Bluetooth adapter unavailable".to_string(), error.clone()),
+        Event::AdapterRestored => (
+            "Bluetooth adapter available again".to_string(),
+            "Proximity unlock works again".to_string(),
+        ),
+    };
+    std::thread::spawn(move || {
+        let result = Connection::new_session().and_then(|connection| {
+            let proxy = connection.with_proxy(NOTIFY_DEST, NOTIFY_PATH, DBUS_TIMEOUT);
+            notify(&proxy, &summary, &body, vec![], URGENCY_NORMAL, DEFAULT_EXPIRY).map(|_| ())
+        });
+        if let Err(e) = result {
+            error!("Failed to show notification: {}", e);
+        }
+    });
+}
+
+/// Shows a notification with Allow and Deny buttons and waits up to `timeout` for
+/// the answer. Dismissing the notification or not answering in time counts as Deny.
+/// Blocks, so call it from a blocking task.
+pub fn confirm(summary: &str, body: &str, timeout: Duration) -> Result<bool, Box<dyn Error>> {
+    let connection = Connection::new_session()?;
+    let proxy = connection.with_proxy(NOTIFY_DEST, NOTIFY_PATH, DBUS_TIMEOUT);
+    // Only the notification server may answer, not any client on the bus. Asking it
+    // for its information first starts it if needed, so that it has an owner.
+    let _: (String, String, String, String) = proxy.method_call(NOTIFY_IFACE, "GetServerInformation", ())?;
+    let bus = connection.with_proxy(BUS_DEST, BUS_PATH, DBUS_TIMEOUT);
+    let (server,): (String,) = bus.method_call(BUS_DEST, "GetNameOwner", (NOTIFY_DEST,))?;
+    // Answers by notification id; other notifications' signals arrive here too.
+    let answers: Arc<Mutex<Vec<(u32, bool)>>> = Arc::new(Mutex::new(vec![]));
+    // Subscribe before showing the notification, so that a quick click isn't missed.
+    let invoked = answers.clone();
+    connection.add_match(
+        MatchRule::new_signal(NOTIFY_IFACE, "ActionInvoked").with_sender(server.clone()),
+        move |(id, action): (u32, String), _, _| {
+            invoked.lock().unwrap().push((id, action == ALLOW_ACTION));
+            true
+        },
+    )?;
+    let closed = answers.clone();
+    connection.add_match(
+        MatchRule::new_signal(NOTIFY_IFACE, "NotificationClosed").with_sender(server),
+        move |(id, _reason): (u32, u32), _, _| {
+            closed.lock().unwrap().push((id, false));
+            true
+        },
+    )?;
+
+    let actions = vec![ALLOW_ACTION, "Allow", DENY_ACTION, "Deny"];
+    let id = notify(&proxy, summary, body, actions, URGENCY_CRITICAL, timeout.as_millis() as i32)?;
+
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        connection.process(Duration::from_millis(200))?;
+        // An action is followed by the closing of the notification; the first wins.
+        if let Some((_, allowed)) = answers.lock().unwrap().iter().find(|(answered, _)| *answered == id) {
+            return Ok(*allowed);
+        }
+    }
+    let _: Result<(), _> = proxy.method_call(NOTIFY_IFACE, "CloseNotification", (id,));
+    Ok(false)
+}
+
+/// Calls `Notify` and returns the id of the new notification.
+fn notify(
+    proxy: &Proxy<&Connection>,
+    summary: &str,
+    body: &str,
+    actions: Vec<&str>,
+    urgency: u8,
+    expire_timeout: i32,
+) -> Result<u32, dbus::Error> {
+    let mut hints = PropMap::new();
+    hints.insert("urgency".to_string(), Variant(Box::new(urgency)));
+    let (id,): (u32,) = proxy.method_call(
+        NOTIFY_IFACE,
+        "Notify",
+        (APP_NAME, 0u32, ICON, summary, body, actions, hints, expire_timeout),
+    )?;
+    Ok(id)
+}
@@ -0,0 +1,70 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+use std::ffi::{CStr, CString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use crate::unlocker::service::get_home_dir;
+
+const APP_DIR: &str = "bunlock";
+const CONFIG_FALLBACK: &str = ".config";
+const STATE_FALLBACK: &str = ".local/state";
+
+/// `$XDG_CONFIG_HOME/bunlock`, or `~/.config/bunlock` when the variable is unset.
+pub fn config_dir() -> PathBuf {
+    xdg_dir("XDG_CONFIG_HOME", CONFIG_FALLBACK).join(APP_DIR)
+}
+
+/// `$XDG_STATE_HOME/bunlock`, or `~/.local/state/bunlock` when the variable is unset.
+pub fn state_dir() -> PathBuf {
+    xdg_dir("XDG_STATE_HOME", STATE_FALLBACK).join(APP_DIR)
+}
+
+/// The default config dir of another user, for code running on their behalf as root
+/// (PAM), where their XDG variables aren't known.
+pub fn config_dir_in(home: &Path) -> PathBuf {
+    home.join(CONFIG_FALLBACK).join(APP_DIR)
+}
+
+/// Like [`config_dir_in`], for the state dir.
+pub fn state_dir_in(home: &Path) -> PathBuf {
+    home.join(STATE_FALLBACK).join(APP_DIR)
+}
+
+/// A user's home directory, uid and gid from the password database.
+pub fn lookup_user(name: &str) -> Option<(PathBuf, u32, u32)> {
+    let name = CString::new(name).ok()?;
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0 as libc::c_char; 4096];
+    let mut result: *mut libc::passwd = std::ptr::null_mut();
+    let status = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut passwd,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut result,
+        )
+    };
+    if status != 0 || result.is_null() || passwd.pw_dir.is_null() {
+        return None;
+    }
+    let home = unsafe { CStr::from_ptr(passwd.pw_dir) };
+    let home = PathBuf::from(std::ffi::OsStr::from_bytes(home.to_bytes()));
+    Some((home, passwd.pw_uid, passwd.pw_gid))
+}
+
+/// Resolves an XDG base directory. Per the specification, relative paths in the
+/// variable are invalid and ignored.
+fn xdg_dir(variable: &str, fallback: &str) -> PathBuf {
+    match std::env::var_os(variable).map(PathBuf::from) {
+        Some(dir) if dir.is_absolute() => dir,
+        _ => PathBuf::from(get_home_dir()).join(fallback),
+    }
+}
@@ -0,0 +1,73 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+use std::error::Error;
+use std::fs::create_dir_all;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::unlocker::{paths, privileges};
+
+const LAST_AUTH_FILE: &str = "last_auth";
+
+/// File holding the time of the last password authentication, as Unix seconds.
+pub fn last_auth_path() -> PathBuf {
+    paths::state_dir().join(LAST_AUTH_FILE)
+}
+
+/// Like [`last_auth_path`], for another user's file.
+pub fn last_auth_path_in(home: &Path) -> PathBuf {
+    paths::state_dir_in(home).join(LAST_AUTH_FILE)
+}
+
+fn record(path: &Path) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent)?;
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    std::fs::write(path, now.to_string())?;
+    Ok(())
+}
+
+/// Records an authentication from a PAM `pam_exec` hook in the `auth` phase. When it
+/// runs as root on behalf of `PAM_USER`, it switches to that user first, so that
+/// nothing in their home directory is written with root privileges.
+pub fn record_from_pam() -> Result<PathBuf, Box<dyn Error>> {
+    if std::env::var("PAM_TYPE").is_ok_and(|pam_type| pam_type != "auth") {
+        return Err("Must run in the PAM auth phase, after the password module".into());
+    }
+    let Some(user) = std::env::var("PAM_USER").ok().filter(|u| !u.is_empty()) else {
+        let path = last_auth_path();
+        record(&path)?;
+        return Ok(path);
+    };
+    let (home, uid, gid) = paths::lookup_user(&user).ok_or(format!("Unknown user {}", user))?;
+    let euid = unsafe { libc::geteuid() };
+    let path = if euid == 0 {
+        privileges::drop_to(uid, gid)?;
+        last_auth_path_in(&home)
+    } else if euid == uid {
+        last_auth_path()
+    } else {
+        return Err(format!("Can't record an authentication of {} as another user", user).into());
+    };
+    record(&path)?;
+    Ok(path)
+}
+
+pub fn last(path: &Path) -> Option<SystemTime> {
+    let contents = std::fs::read_to_string(path).ok()?;
+    let seconds: u64 = contents.trim().parse().ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+pub fn is_recent(path: &Path, max_age: Duration) -> bool {
+    last(path)
+        .and_then(|time| SystemTime::now().duration_since(time).ok())
+        .is_some_and(|age| age <= max_age)
+}
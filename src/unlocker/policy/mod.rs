@@ -0,0 +1,46 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+pub mod auth;
+pub mod network;
+pub mod rate_limit;
+pub mod schedule;
+
+use std::error::Error;
+use std::path::Path;
+use std::time::{Duration, Instant};
+use crate::unlocker::config::Config;
+use rate_limit::RateLimiter;
+
+/// Returns why an unlock must not happen now even though the device is in range.
+/// Shared by the daemon and the PAM module. Fails when the network state is needed
+/// but can't be read, which callers treat as a denial too.
+pub async fn denial_reason(
+    config: &Config,
+    rate_limiter: &mut RateLimiter,
+    last_auth: &Path,
+) -> Result<Option<String>, Box<dyn Error>> {
+    if let Some(reason) = rate_limiter.denial_reason(&config.rate_limit, Instant::now()) {
+        return Ok(Some(reason));
+    }
+    if !schedule::allows(&config.schedule, chrono::Local::now().naive_local()) {
+        return Ok(Some("outside schedule".to_string()));
+    }
+    if let Some(hours) = config.require_auth_within_hours {
+        if !auth::is_recent(last_auth, Duration::from_secs(u64::from(hours) * 3600)) {
+            return Ok(Some(format!("no password authentication in the last {} hours", hours)));
+        }
+    }
+    if let Some(policy) = &config.network {
+        if !policy.allows(&network::active_connections().await?) {
+            return Ok(Some("not on an allowed network".to_string()));
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,194 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+use dbus::blocking::Connection;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+const NM_DEST: &str = "org.freedesktop.NetworkManager";
+const NM_PATH: &str = "/org/freedesktop/NetworkManager";
+const NM_ACTIVE_IFACE: &str = "org.freedesktop.NetworkManager.Connection.Active";
+const NM_AP_IFACE: &str = "org.freedesktop.NetworkManager.AccessPoint";
+const NM_DEVICE_IFACE: &str = "org.freedesktop.NetworkManager.Device";
+const WIRED_TYPE: &str = "802-3-ethernet";
+const WIRELESS_TYPE: &str = "802-11-wireless";
+const DBUS_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Networks on which proximity unlock is permitted. Unlock is allowed when any
+/// active connection matches one of the entries.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default)]
+pub struct NetworkPolicy {
+    /// Wi-Fi network names.
+    pub allowed_ssids: Vec<String>,
+    /// Wi-Fi access point MAC addresses, which unlike a name can't simply be copied
+    /// by another network.
+    pub allowed_bssids: Vec<String>,
+    /// NetworkManager connection UUIDs, as listed by `nmcli connection show`.
+    pub allowed_connections: Vec<String>,
+    /// Network interfaces, such as a VPN's `wg0`.
+    pub allowed_interfaces: Vec<String>,
+    /// Any wired Ethernet connection.
+    pub allow_wired: bool,
+}
+
+impl NetworkPolicy {
+    pub fn allows(&self, connections: &[ActiveConnection]) -> bool {
+        connections.iter().any(|connection| {
+            self.allowed_connections.contains(&connection.uuid)
+                || (self.allow_wired && connection.kind == WIRED_TYPE)
+                || connection.ssid.as_ref().is_some_and(|ssid| self.allowed_ssids.contains(ssid))
+                || connection.bssid.as_ref().is_some_and(|bssid| {
+                    self.allowed_bssids.iter().any(|allowed| allowed.eq_ignore_ascii_case(bssid))
+                })
+                || connection.interfaces.iter().any(|interface| self.allowed_interfaces.contains(interface))
+        })
+    }
+
+    /// Whether no connection can match.
+    pub fn is_empty(&self) -> bool {
+        self.allowed_ssids.is_empty()
+            && self.allowed_bssids.is_empty()
+            && self.allowed_connections.is_empty()
+            && self.allowed_interfaces.is_empty()
+            && !self.allow_wired
+    }
+}
+
+/// A NetworkManager connection that is currently active.
+#[derive(Debug, Clone, Default)]
+pub struct ActiveConnection {
+    pub id: String,
+    pub uuid: String,
+    pub kind: String,
+    pub ssid: Option<String>,
+    pub bssid: Option<String>,
+    pub interfaces: Vec<String>,
+}
+
+impl fmt::Display for ActiveConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.ssid, &self.bssid) {
+            (Some(ssid), Some(bssid)) => write!(f, "Wi-Fi \"{}\" {} ({})", ssid, bssid, self.uuid)?,
+            (Some(ssid), None) => write!(f, "Wi-Fi \"{}\" ({})", ssid, self.uuid)?,
+            _ => write!(f, "{} \"{}\" ({})", self.kind, self.id, self.uuid)?,
+        }
+        if !self.interfaces.is_empty() {
+            write!(f, " on {}", self.interfaces.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Asks NetworkManager for the active connections. The D-Bus calls block, so they
+/// run on a blocking thread.
+pub async fn active_connections() -> Result<Vec<ActiveConnection>, Box<dyn Error>> {
+    Ok(tokio::task::spawn_blocking(read_active_connections).await??)
+}
+
+fn read_active_connections() -> Result<Vec<ActiveConnection>, dbus::Error> {
+    let connection = Connection::new_system()?;
+    let manager = connection.with_proxy(NM_DEST, NM_PATH, DBUS_TIMEOUT);
+    let paths: Vec<dbus::Path<'static>> = manager.get(NM_DEST, "ActiveConnections")?;
+    let mut connections = vec![];
+    for path in paths {
+        let active = connection.with_proxy(NM_DEST, &path, DBUS_TIMEOUT);
+        let kind: String = active.get(NM_ACTIVE_IFACE, "Type")?;
+        let access_point: dbus::Path<'static> = active.get(NM_ACTIVE_IFACE, "SpecificObject")?;
+        let (ssid, bssid) = if kind == WIRELESS_TYPE && &*access_point != "/" {
+            let access_point = connection.with_proxy(NM_DEST, access_point, DBUS_TIMEOUT);
+            let ssid: Vec<u8> = access_point.get(NM_AP_IFACE, "Ssid")?;
+            let bssid: String = access_point.get(NM_AP_IFACE, "HwAddress")?;
+            (Some(String::from_utf8_lossy(&ssid).into_owned()), Some(bssid))
+        } else {
+            (None, None)
+        };
+        let devices: Vec<dbus::Path<'static>> = active.get(NM_ACTIVE_IFACE, "Devices")?;
+        let mut interfaces = vec![];
+        for device in devices {
+            let device = connection.with_proxy(NM_DEST, device, DBUS_TIMEOUT);
+            interfaces.push(device.get(NM_DEVICE_IFACE, "Interface")?);
+        }
+        connections.push(ActiveConnection {
+            id: active.get(NM_ACTIVE_IFACE, "Id")?,
+            uuid: active.get(NM_ACTIVE_IFACE, "Uuid")?,
+            kind,
+            ssid,
+            bssid,
+            interfaces,
+        });
+    }
+    Ok(connections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wifi(ssid: &str, bssid: &str) -> ActiveConnection {
+        ActiveConnection {
+            id: ssid.to_string(),
+            uuid: "5f0b7c9e-8a51-4c7e-9d43-0e0a7a8d2b1c".to_string(),
+            kind: WIRELESS_TYPE.to_string(),
+            ssid: Some(ssid.to_string()),
+            bssid: Some(bssid.to_string()),
+            interfaces: vec!["wlan0".to_string()],
+        }
+    }
+
+    fn wired() -> ActiveConnection {
+        ActiveConnection {
+            id: "Wired connection 1".to_string(),
+            uuid: "0c1e7a3d-2b5f-4e8a-9c6d-1f2a3b4c5d6e".to_string(),
+            kind: WIRED_TYPE.to_string(),
+            interfaces: vec!["eth0".to_string()],
+            ..ActiveConnection::default()
+        }
+    }
+
+    #[test]
+    fn matches_ssids() {
+        let policy = NetworkPolicy { allowed_ssids: vec!["Office".to_string()], ..NetworkPolicy::default() };
+        assert!(policy.allows(&[wired(), wifi("Office", "00:11:22:33:44:55")]));
+        assert!(!policy.allows(&[wifi("Café", "00:11:22:33:44:55")]));
+        assert!(!policy.allows(&[]));
+    }
+
+    #[test]
+    fn matches_bssids_in_any_case() {
+        let policy = NetworkPolicy { allowed_bssids: vec!["aa:bb:cc:dd:ee:ff".to_string()], ..NetworkPolicy::default() };
+        assert!(policy.allows(&[wifi("Office", "AA:BB:CC:DD:EE:FF")]));
+        // The same name on another access point doesn't count.
+        assert!(!policy.allows(&[wifi("Office", "00:11:22:33:44:55")]));
+    }
+
+    #[test]
+    fn matches_interfaces_and_wired_connections() {
+        let policy = NetworkPolicy { allowed_interfaces: vec!["eth0".to_string()], ..NetworkPolicy::default() };
+        assert!(policy.allows(&[wired()]));
+        assert!(!policy.allows(&[wifi("Office", "00:11:22:33:44:55")]));
+        let policy = NetworkPolicy { allow_wired: true, ..NetworkPolicy::default() };
+        assert!(policy.allows(&[wired()]));
+        assert!(!policy.allows(&[wifi("Office", "00:11:22:33:44:55")]));
+    }
+
+    #[test]
+    fn matches_connection_uuids() {
+        let policy = NetworkPolicy {
+            allowed_connections: vec!["0c1e7a3d-2b5f-4e8a-9c6d-1f2a3b4c5d6e".to_string()],
+            ..NetworkPolicy::default()
+        };
+        assert!(policy.allows(&[wired()]));
+        assert!(!policy.allows(&[wifi("Office", "00:11:22:33:44:55")]));
+        assert!(NetworkPolicy::default().is_empty() && !policy.is_empty());
+    }
+}
@@ -0,0 +1,189 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Unlocks older than this count towards no limit.
+pub const WINDOW: Duration = Duration::from_secs(3600);
+
+/// Limits on how often the daemon unlocks the session. A value of 0 turns the
+/// respective limit off, and all of them are off by default.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct RateLimitSettings {
+    /// Minimum time between two unlocks.
+    pub cooldown_seconds: u64,
+    /// Maximum number of unlocks within any hour.
+    pub max_unlocks_per_hour: u32,
+    /// Number of re-locks of a proximity-unlocked session after which proximity
+    /// unlock is suspended until the next manual unlock.
+    pub max_relocks: u32,
+    /// Time span in which the re-locks are counted.
+    pub relock_window_seconds: u64,
+}
+
+impl Default for RateLimitSettings {
+    fn default() -> Self {
+        RateLimitSettings {
+            cooldown_seconds: 0,
+            max_unlocks_per_hour: 0,
+            max_relocks: 0,
+            relock_window_seconds: 120,
+        }
+    }
+}
+
+/// Tracks recent unlocks and re-locks to enforce [`RateLimitSettings`].
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    unlocks: VecDeque<Instant>,
+    relocks: VecDeque<Instant>,
+    suspended: bool,
+}
+
+impl RateLimiter {
+    /// A limiter that knows of earlier unlocks, given by how long ago they were.
+    /// Re-locks aren't known, so it is never suspended.
+    pub fn with_unlocks(ages: impl IntoIterator<Item = Duration>, now: Instant) -> RateLimiter {
+        let mut unlocks: Vec<Instant> = ages.into_iter().filter_map(|age| now.checked_sub(age)).collect();
+        unlocks.sort();
+        RateLimiter { unlocks: unlocks.into(), ..RateLimiter::default() }
+    }
+
+    /// Returns why an unlock is not allowed now, if it isn't.
+    pub fn denial_reason(&mut self, settings: &RateLimitSettings, now: Instant) -> Option<String> {
+        if self.suspended {
+            return Some("suspended after repeated re-locks, unlock manually to resume".to_string());
+        }
+        prune(&mut self.unlocks, WINDOW, now);
+        if let Some(last) = self.unlocks.back() {
+            let cooldown = Duration::from_secs(settings.cooldown_seconds);
+            if now.duration_since(*last) < cooldown {
+                return Some(format!("cooldown of {} seconds after the last unlock", settings.cooldown_seconds));
+            }
+        }
+        let max = settings.max_unlocks_per_hour as usize;
+        if max > 0 && self.unlocks.len() >= max {
+            return Some(format!("limit of {} unlocks per hour reached", max));
+        }
+        None
+    }
+
+    pub fn unlocked(&mut self, now: Instant) {
+        self.unlocks.push_back(now);
+    }
+
+    /// Records that a session unlocked by the daemon was locked again. Returns `true`
+    /// when this suspends proximity unlock.
+    pub fn relocked(&mut self, settings: &RateLimitSettings, now: Instant) -> bool {
+        if settings.max_relocks == 0 || self.suspended {
+            return false;
+        }
+        self.relocks.push_back(now);
+        prune(&mut self.relocks, Duration::from_secs(settings.relock_window_seconds), now);
+        if self.relocks.len() >= settings.max_relocks as usize {
+            self.suspended = true;
+            self.relocks.clear();
+        }
+        self.suspended
+    }
+
+    /// A manual unlock lifts a suspension.
+    pub fn manual_unlock(&mut self) {
+        self.suspended = false;
+        self.relocks.clear();
+    }
+}
+
+fn prune(times: &mut VecDeque<Instant>, max_age: Duration, now: Instant) {
+    while times.front().is_some_and(|t| now.duration_since(*t) >= max_age) {
+        times.pop_front();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> RateLimitSettings {
+        RateLimitSettings {
+            cooldown_seconds: 30,
+            max_unlocks_per_hour: 3,
+            max_relocks: 2,
+            relock_window_seconds: 120,
+        }
+    }
+
+    fn secs(seconds: u64) -> Duration {
+        Duration::from_secs(seconds)
+    }
+
+    #[test]
+    fn defaults_never_deny() {
+        let mut limiter = RateLimiter::default();
+        let settings = RateLimitSettings::default();
+        let start = Instant::now();
+        for i in 0..100 {
+            assert_eq!(limiter.denial_reason(&settings, start + secs(i)), None);
+            limiter.unlocked(start + secs(i));
+            assert!(!limiter.relocked(&settings, start + secs(i)));
+        }
+    }
+
+    #[test]
+    fn waits_for_the_cooldown() {
+        let mut limiter = RateLimiter::default();
+        let start = Instant::now();
+        limiter.unlocked(start);
+        assert!(limiter.denial_reason(&settings(), start + secs(29)).is_some());
+        assert_eq!(limiter.denial_reason(&settings(), start + secs(30)), None);
+    }
+
+    #[test]
+    fn limits_unlocks_per_hour() {
+        let mut limiter = RateLimiter::default();
+        let start = Instant::now();
+        for minute in 0..3 {
+            limiter.unlocked(start + secs(minute * 60));
+        }
+        assert!(limiter.denial_reason(&settings(), start + secs(600)).is_some());
+        // The first unlock drops out of the hour.
+        assert_eq!(limiter.denial_reason(&settings(), start + secs(3600)), None);
+    }
+
+    #[test]
+    fn suspends_after_relocks_until_a_manual_unlock() {
+        let mut limiter = RateLimiter::default();
+        let start = Instant::now();
+        assert!(!limiter.relocked(&settings(), start));
+        assert!(limiter.relocked(&settings(), start + secs(60)));
+        assert!(limiter.denial_reason(&settings(), start + secs(3600)).is_some());
+        limiter.manual_unlock();
+        assert_eq!(limiter.denial_reason(&settings(), start + secs(3600)), None);
+    }
+
+    #[test]
+    fn counts_relocks_within_the_window_only() {
+        let mut limiter = RateLimiter::default();
+        let start = Instant::now();
+        assert!(!limiter.relocked(&settings(), start));
+        assert!(!limiter.relocked(&settings(), start + secs(120)));
+        assert!(limiter.relocked(&settings(), start + secs(150)));
+    }
+
+    #[test]
+    fn knows_earlier_unlocks() {
+        let now = Instant::now() + secs(7200);
+        let mut limiter = RateLimiter::with_unlocks([secs(10), secs(1200), secs(600)], now);
+        assert!(limiter.denial_reason(&settings(), now).is_some());
+        assert_eq!(limiter.denial_reason(&settings(), now + secs(2400)), None);
+    }
+}
@@ -0,0 +1,144 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+use chrono::{Datelike, NaiveDateTime, NaiveTime, Weekday};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+
+const TIME_FORMAT: &str = "%H:%M";
+
+/// A time window in which proximity unlock is allowed, e.g. weekdays 08:00–19:00.
+/// A window whose end is before its start spans midnight and belongs to the day it starts on.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScheduleRule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub days: Vec<Weekday>,
+    #[serde(serialize_with = "serialize_time", deserialize_with = "deserialize_time")]
+    pub start: NaiveTime,
+    #[serde(serialize_with = "serialize_time", deserialize_with = "deserialize_time")]
+    pub end: NaiveTime,
+}
+
+impl ScheduleRule {
+    pub fn contains(&self, now: NaiveDateTime) -> bool {
+        let weekday = now.weekday();
+        let time = now.time();
+        if self.start <= self.end {
+            self.days.contains(&weekday) && self.start <= time && time < self.end
+        } else {
+            (self.days.contains(&weekday) && time >= self.start)
+                || (self.days.contains(&weekday.pred()) && time < self.end)
+        }
+    }
+}
+
+impl fmt::Display for ScheduleRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(name) = &self.name {
+            write!(f, "{}: ", name)?;
+        }
+        let days: Vec<String> = self.days.iter().map(|d| d.to_string()).collect();
+        write!(
+            f,
+            "{} {}–{}",
+            days.join(", "),
+            self.start.format(TIME_FORMAT),
+            self.end.format(TIME_FORMAT)
+        )
+    }
+}
+
+/// The first rule covering `now`. With no rules at all there is no restriction,
+/// which is reported by `allows` rather than here.
+pub fn active_rule(rules: &[ScheduleRule], now: NaiveDateTime) -> Option<&ScheduleRule> {
+    rules.iter().find(|rule| rule.contains(now))
+}
+
+pub fn allows(rules: &[ScheduleRule], now: NaiveDateTime) -> bool {
+    rules.is_empty() || active_rule(rules, now).is_some()
+}
+
+fn serialize_time<S>(time: &NaiveTime, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    time.format(TIME_FORMAT).to_string().serialize(serializer)
+}
+
+fn deserialize_time<'de, D>(deserializer: D) -> Result<NaiveTime, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    NaiveTime::parse_from_str(&value, TIME_FORMAT)
+        .map_err(|_| serde::de::Error::custom(format!("invalid time \"{}\", expected HH:MM", value)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+
+    fn rule(days: &[Weekday], start: &str, end: &str) -> ScheduleRule {
+        ScheduleRule {
+            name: None,
+            days: days.to_vec(),
+            start: NaiveTime::parse_from_str(start, TIME_FORMAT).unwrap(),
+            end: NaiveTime::parse_from_str(end, TIME_FORMAT).unwrap(),
+        }
+    }
+
+    /// 2024-01-01 was a Monday.
+    fn at(day: u32, time: &str) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 1, day)
+            .unwrap()
+            .and_time(NaiveTime::parse_from_str(time, TIME_FORMAT).unwrap())
+    }
+
+    #[test]
+    fn contains_times_within_the_day() {
+        let office = rule(&[Weekday::Mon, Weekday::Tue], "08:00", "19:00");
+        assert!(office.contains(at(1, "08:00")));
+        assert!(office.contains(at(2, "18:59")));
+        assert!(!office.contains(at(1, "19:00")));
+        assert!(!office.contains(at(1, "07:59")));
+        assert!(!office.contains(at(3, "12:00")));
+    }
+
+    #[test]
+    fn wraps_past_midnight() {
+        let night = rule(&[Weekday::Fri], "22:00", "06:00");
+        assert!(night.contains(at(5, "22:00")));
+        assert!(night.contains(at(5, "23:59")));
+        // The early hours belong to the day the window started on.
+        assert!(night.contains(at(6, "00:00")));
+        assert!(night.contains(at(6, "05:59")));
+        assert!(!night.contains(at(6, "06:00")));
+        assert!(!night.contains(at(5, "05:00")));
+        assert!(!night.contains(at(6, "22:00")));
+    }
+
+    #[test]
+    fn no_rules_allow_any_time() {
+        assert!(allows(&[], at(1, "03:00")));
+        let rules = [rule(&[Weekday::Mon], "08:00", "19:00")];
+        assert!(allows(&rules, at(1, "12:00")));
+        assert!(!allows(&rules, at(1, "03:00")));
+    }
+
+    #[test]
+    fn round_trips_through_json() {
+        let json = r#"{"days":["Mon","Sun"],"start":"22:30","end":"06:00"}"#;
+        let parsed: ScheduleRule = serde_json::from_str(json).unwrap();
+        assert_eq!(parsed, rule(&[Weekday::Mon, Weekday::Sun], "22:30", "06:00"));
+        assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+        assert!(serde_json::from_str::<ScheduleRule>(r#"{"days":[],"start":"25:00","end":"06:00"}"#).is_err());
+    }
+}
@@ -0,0 +1,57 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+use log::debug;
+use std::error::Error;
+use std::time::Duration;
+use tokio::time::{sleep, timeout};
+use crate::unlocker::bluetooth::device::Device;
+use crate::unlocker::config::{Config, PresenceMode};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Checks once whether the configured device is in range, scanning for it for at
+/// most `max_wait`. Unlike the daemon this doesn't install signal handlers, so it
+/// is safe to call from inside other programs (PAM).
+pub async fn check(config: &Config, max_wait: Duration) -> Result<bool, Box<dyn Error>> {
+    let device = read(config, max_wait).await?;
+    Ok(config.is_in_range(&device))
+}
+
+/// Like [`check`], but returns the device with its last readings; it is in range
+/// when [`Config::is_in_range`] says so.
+pub async fn read(config: &Config, max_wait: Duration) -> Result<Device, Box<dyn Error>> {
+    let mut device = config.device.clone();
+    device.scan_options = config.scan_options();
+    device.set_scanning(true).await?;
+    let found = timeout(max_wait, wait_in_range(config, &mut device)).await.unwrap_or(Ok(false));
+    if config.presence_mode == PresenceMode::Connection {
+        device.disconnect().await;
+    }
+    device.set_scanning(false).await.ok();
+    found.map(|_| device)
+}
+
+async fn wait_in_range(config: &Config, device: &mut Device) -> Result<bool, Box<dyn Error>> {
+    loop {
+        if !device.is_found() && !device.search_once().await? {
+            sleep(POLL_INTERVAL).await;
+            continue;
+        }
+        let rssi = match config.presence_mode {
+            PresenceMode::Advertisement => device.update_rssi().await,
+            PresenceMode::Connection => device.update_connection_rssi().await,
+        };
+        if config.is_in_range(device) {
+            debug!("{} is in range (RSSI {})", device, rssi);
+            return Ok(true);
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+}
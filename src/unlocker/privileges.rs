@@ -0,0 +1,75 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+use std::error::Error;
+use std::io;
+
+/// Permanently switches the process to the user, for code started as root on their
+/// behalf that only needs to touch their files.
+pub fn drop_to(uid: u32, gid: u32) -> Result<(), Box<dyn Error>> {
+    unsafe {
+        check(libc::setgroups(1, &gid))?;
+        check(libc::setgid(gid))?;
+        check(libc::setuid(uid))?;
+    }
+    if unsafe { libc::setuid(0) } == 0 {
+        return Err("Failed to drop root privileges".into());
+    }
+    Ok(())
+}
+
+/// Runs with the user's effective uid, gid and groups until dropped, for code in a
+/// root process (the PAM module) that reads files the user controls. Does nothing
+/// when the process isn't root.
+pub struct EffectiveUser {
+    previous: Option<(libc::uid_t, libc::gid_t, Vec<libc::gid_t>)>,
+}
+
+impl EffectiveUser {
+    pub fn switch(uid: u32, gid: u32) -> Result<EffectiveUser, Box<dyn Error>> {
+        let euid = unsafe { libc::geteuid() };
+        if euid != 0 {
+            return Ok(EffectiveUser { previous: None });
+        }
+        let egid = unsafe { libc::getegid() };
+        let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+        let mut groups = vec![0; usize::try_from(count).map_err(|_| io::Error::last_os_error())?];
+        let count = unsafe { libc::getgroups(count, groups.as_mut_ptr()) };
+        groups.truncate(usize::try_from(count).map_err(|_| io::Error::last_os_error())?);
+        // Restores whatever was changed if one of the calls fails.
+        let user = EffectiveUser { previous: Some((euid, egid, groups)) };
+        unsafe {
+            check(libc::setgroups(1, &gid))?;
+            check(libc::setegid(gid))?;
+            check(libc::seteuid(uid))?;
+        }
+        Ok(user)
+    }
+}
+
+impl Drop for EffectiveUser {
+    fn drop(&mut self) {
+        if let Some((uid, gid, groups)) = self.previous.take() {
+            // Failing leaves the process less privileged, never more.
+            unsafe {
+                libc::seteuid(uid);
+                libc::setegid(gid);
+                libc::setgroups(groups.len(), groups.as_ptr());
+            }
+        }
+    }
+}
+
+fn check(result: libc::c_int) -> io::Result<()> {
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
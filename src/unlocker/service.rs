@@ -9,9 +9,10 @@
 
 use home::home_dir;
 use std::error::Error as StdError;
+use std::path::Path;
 use std::process::Command;
 
-const SERVICE_NAME: &str = "bunlock.service";
+pub const SERVICE_NAME: &str = "bunlock.service";
 
 pub fn get_home_dir() -> String {
     let home_dir = home_dir().expect("Failed to get home directory");
@@ -21,17 +22,22 @@ pub fn get_home_dir() -> String {
         .to_string()
 }
 
-pub fn create_service() -> Result<(), Box<dyn StdError>> {
+/// Writes the unit file. The config path is passed to the daemon through
+/// `BUNLOCK_CONFIG`, so that it reads the same file as the CLI.
+pub fn create_service(config_path: &Path) -> Result<(), Box<dyn StdError>> {
     let service_file_path = format!("~/.config/systemd/user/{}", SERVICE_NAME);
     let bin_file_path = "~/.local/bin/bunlock/bunlock".replace("~", &get_home_dir());
+    let config_path = std::path::absolute(config_path)?;
     let service_file_content = format!(
         "[Unit]
 Description=BUnlock Bluetooth Unlocker Daemon
 After=network.target
 
 [Service]
-ExecStart={}
+ExecStart=\"{}\"
+ExecReload=/bin/kill -HUP $MAINPID
 Environment=RUST_LOG=info
+Environment=\"BUNLOCK_CONFIG={}\"
 StandardOutput=journal
 StandardError=journal
 Restart=always
@@ -40,7 +46,8 @@ RestartSec=5
 [Install]
 WantedBy=default.target
 ",
-        bin_file_path
+        escape(&bin_file_path),
+        escape(&config_path.to_string_lossy())
     );
 
     let expanded_path = service_file_path.replace("~", &get_home_dir());
@@ -52,6 +59,12 @@ WantedBy=default.target
     Ok(())
 }
 
+/// Escapes a value for a double-quoted unit file setting, where `%` starts a
+/// specifier.
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('%', "%%")
+}
+
 pub fn remove_service() -> Result<(), Box<dyn StdError>> {
     if !exists()? {
         return Err("Service does not exist".into());
@@ -105,11 +118,8 @@ pub fn is_running() -> Result<bool, Box<dyn StdError>> {
     Ok(output_str == "active")
 }
 
-pub fn enable() -> Result<(), Box<dyn StdError>> {
-    let service_exists = exists()?;
-    if !service_exists {
-        create_service()?;
-    }
+pub fn enable(config_path: &Path) -> Result<(), Box<dyn StdError>> {
+    create_service(config_path)?;
     service_command("enable")
 }
 
@@ -160,6 +170,26 @@ pub fn restart() -> Result<(), Box<dyn StdError>> {
     service_command("restart")
 }
 
+/// Asks the running daemon to reload its config. Sends SIGHUP directly so that it
+/// also works with unit files created before `ExecReload` was added.
+pub fn reload() -> Result<(), Box<dyn StdError>> {
+    if !is_running()? {
+        return Err("Service is not running".into());
+    }
+
+    let status = Command::new("systemctl")
+        .arg("--user")
+        .arg("kill")
+        .arg("--signal=SIGHUP")
+        .arg(SERVICE_NAME)
+        .status()?;
+
+    if !status.success() {
+        return Err("Failed to reload service".into());
+    }
+    Ok(())
+}
+
 fn service_command(command: &str) -> Result<(), Box<dyn StdError>> {
     let status = Command::new("systemctl")
         .arg("--user")
@@ -172,3 +202,14 @@ fn service_command(command: &str) -> Result<(), Box<dyn StdError>> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escapes_unit_file_values() {
+        assert_eq!(escape("/home/a/.config/bunlock/config.toml"), "/home/a/.config/bunlock/config.toml");
+        assert_eq!(escape("/home/a/100%/\"x\\"), "/home/a/100%%/\\\"x\\\\");
+    }
+}
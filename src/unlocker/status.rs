@@ -0,0 +1,77 @@
+/*
+ * Copyright (c) 2024 Ilia MatthewAllDev Kuvarzin
+ *
+ * This file is part of the BUnlock project.
+ *
+ * BUnlock is licensed under the GNU General Public License v3.0 (GPL-3.0).
+ * See the LICENSE file for details.
+ */
+
+use chrono::{DateTime, Local};
+use std::time::Duration;
+use crate::unlocker::config::Config;
+use crate::unlocker::policy::{auth, network, schedule};
+use crate::unlocker::service;
+
+/// Prints the configured device, the service state and whether each unlock policy
+/// currently permits proximity unlock.
+pub async fn print(config: &Config) {
+    let service_state = match service::is_running() {
+        Ok(true) => "active".to_string(),
+        Ok(false) => "inactive".to_string(),
+        Err(e) => format!("unknown ({})", e),
+    };
+    println!("Config:   {}", config.path.display());
+    println!("Device:   {}", config.device);
+    println!("Service:  {}", service_state);
+    println!("Mode:     {}", config.unlock_mode);
+    println!("Schedule: {}", schedule_status(config));
+    println!("Network:  {}", network_status(config).await);
+    println!("Auth:     {}", auth_status(config));
+}
+
+fn schedule_status(config: &Config) -> String {
+    if config.schedule.is_empty() {
+        return "no rules, unlock always allowed".to_string();
+    }
+    match schedule::active_rule(&config.schedule, Local::now().naive_local()) {
+        Some(rule) => format!("active rule {}", rule),
+        None => "outside schedule, unlock disabled".to_string(),
+    }
+}
+
+async fn network_status(config: &Config) -> String {
+    let Some(policy) = &config.network else {
+        return "no policy, unlock allowed on any network".to_string();
+    };
+    match network::active_connections().await {
+        Ok(connections) => {
+            let names: Vec<String> = connections.iter().map(|c| c.to_string()).collect();
+            let connected = if names.is_empty() { "not connected".to_string() } else { names.join(", ") };
+            if policy.allows(&connections) {
+                format!("{}, unlock allowed", connected)
+            } else {
+                format!("{}, unlock disabled", connected)
+            }
+        }
+        Err(e) => format!("unknown ({}), unlock disabled", e),
+    }
+}
+
+fn auth_status(config: &Config) -> String {
+    let last = match auth::last(&auth::last_auth_path()) {
+        Some(time) => format!(
+            "last password authentication {}",
+            DateTime::<Local>::from(time).format("%Y-%m-%d %H:%M")
+        ),
+        None => "no password authentication recorded".to_string(),
+    };
+    let Some(hours) = config.require_auth_within_hours else {
+        return format!("{}, not required", last);
+    };
+    if auth::is_recent(&auth::last_auth_path(), Duration::from_secs(u64::from(hours) * 3600)) {
+        format!("{}, unlock allowed (required within {} hours)", last, hours)
+    } else {
+        format!("{}, unlock disabled (required within {} hours)", last, hours)
+    }
+}
@@ -1,13 +1,14 @@
 #!/bin/bash
 
 cd ..
-cargo build --release
+cargo build --release --workspace
 if [ $? -ne 0 ]; then
     echo "Build failed!"
     exit 1
 fi
 mkdir -p release
 cp target/release/bunlock release/
+cp target/release/libpam_bunlock.so release/pam_bunlock.so
 cp tools/install.sh release/
 cp tools/uninstall.sh release/
 
@@ -2,7 +2,7 @@
 
 BIN_PATH="$HOME/.local/bin/bunlock"
 SERVICE_PATH="$HOME/.config/systemd/user/bunlock.service"
-CONFIG_DIR="$HOME/.config/bunlock"
+CONFIG_DIR="${XDG_CONFIG_HOME:-$HOME/.config}/bunlock"
 PROFILE_FILES=("$HOME/.bashrc" "$HOME/.zshrc" "$HOME/.profile")
 
 bunlock service remove